@@ -0,0 +1,525 @@
+use chrono::Utc;
+use libp2p::identity::ed25519::{ Keypair, PublicKey, SecretKey };
+use serde::Serialize;
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::block::{ Block, BlockCandidate, MAX_BLOCK_RECORDS };
+use crate::consensus::{ BlockSignature, ConsensusEngine, ConsensusRecord };
+use crate::error::Error;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+pub const DEFAULT_SLOT_TIME: u64 = 5;
+
+//The validator set in force after a block, together with the votes that have not reached a majority yet.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorityState
+{
+    pub validators: Vec<String>,
+    tally: HashMap<(String, bool), HashSet<String>>,
+}
+
+impl AuthorityState
+{
+    fn leader(&self, slot: u64) -> Option<&String>
+    {
+        if self.validators.is_empty()
+        {
+            return None;
+        }
+
+        self.validators.get((slot % self.validators.len() as u64) as usize)
+    }
+
+    //A vote counts once per validator, a strict majority of the current set adds or removes the target. A validator voted out
+    //takes its pending votes with it.
+    fn apply<P>(&self, block: &Block<P>) -> Self
+    {
+        let mut next = self.clone();
+
+        let Some(signer) = block.signature.as_ref().map(|signature| &signature.signer) else { return next };
+
+        for record in &block.records
+        {
+            let ConsensusRecord::Vote { validator, add } = record else { continue };
+
+            if !next.validators.contains(signer) || next.validators.contains(validator) == *add
+            {
+                continue;
+            }
+
+            let voters = next.tally.entry((validator.clone(), *add)).or_default();
+            voters.insert(signer.clone());
+
+            //Only votes from validators still in the set count towards its majority.
+            let support = voters.iter().filter(|voter| next.validators.contains(voter)).count();
+
+            if support * 2 > next.validators.len()
+            {
+                if *add
+                {
+                    println!("Validator {validator} voted in");
+                    next.validators.push(validator.clone());
+                }
+                else
+                {
+                    println!("Validator {validator} voted out");
+                    next.validators.retain(|current| current != validator);
+
+                    for voters in next.tally.values_mut()
+                    {
+                        voters.remove(validator);
+                    }
+                }
+
+                next.tally.retain(|(target, _), voters| target != validator && !voters.is_empty());
+            }
+        }
+
+        next
+    }
+}
+
+//Validators take turns in fixed slots of unix time, the leader of a slot is validators[slot % len].
+#[derive(Debug)]
+pub struct ProofOfAuthority
+{
+    genesis: AuthorityState,
+    slot_time: u64,
+    key: Option<Keypair>,
+    //The state after each block by hash, with the block's height for pruning.
+    states: Mutex<HashMap<String, (u64, AuthorityState)>>,
+    proposals: Mutex<Vec<ConsensusRecord>>,
+}
+
+impl ProofOfAuthority
+{
+    pub fn new(validators: Vec<String>, slot_time: u64, key: Option<Keypair>) -> Self
+    {
+        Self
+        {
+            genesis: AuthorityState
+            {
+                validators,
+                tally: HashMap::new(),
+            },
+            slot_time: slot_time.max(1),
+            key,
+            states: Mutex::new(HashMap::new()),
+            proposals: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn public_key(&self) -> Option<String>
+    {
+        self.key.as_ref().map(|key| hex::encode(key.public().to_bytes()))
+    }
+
+    fn slot(&self, timestamp: i64) -> u64
+    {
+        timestamp.max(0) as u64 / self.slot_time
+    }
+
+    //The genesis block is created locally by every node, so its state is always the configured set.
+    fn state_after(&self, height: u64, hash: &str) -> Option<AuthorityState>
+    {
+        if height == 0
+        {
+            return Some(self.genesis.clone());
+        }
+
+        self.states.lock().unwrap().get(hash).map(|(_, state)| state.clone())
+    }
+
+    //Anyone in the configured set or in the set after any block we track.
+    fn is_known_validator(&self, signer: &String) -> bool
+    {
+        self.genesis.validators.contains(signer) || self.states.lock().unwrap().values().any(|(_, state)| state.validators.contains(signer))
+    }
+}
+
+impl<P: Payload> ConsensusEngine<P> for ProofOfAuthority
+{
+    //Aims the candidate at the next slot this node leads, our pending votes ride along.
+    fn prepare(&self, parent: &Block<P>, data: P, transactions: Vec<Transaction>) -> BlockCandidate<P>
+    {
+        let first_slot = self.slot(Utc::now().timestamp()).max(self.slot(parent.timestamp) + 1);
+        let mut slot = first_slot;
+
+        if let (Some(state), Some(public)) = (self.state_after(parent.height, &parent.hash), self.public_key())
+        {
+            let turn = (first_slot..first_slot + state.validators.len() as u64).find(|slot| state.leader(*slot) == Some(&public));
+            slot = turn.unwrap_or(first_slot);
+
+            self.proposals.lock().unwrap().retain(|record| match record
+            {
+                ConsensusRecord::Vote { validator, add } => state.validators.contains(validator) != *add,
+                _ => false,
+            });
+        }
+
+        let mut candidate = BlockCandidate::new(parent, data, transactions);
+        candidate.timestamp = (slot * self.slot_time) as i64;
+        candidate.records = self.proposals.lock().unwrap().iter().take(MAX_BLOCK_RECORDS).cloned().collect();
+
+        candidate
+    }
+
+    fn ready_in(&self, candidate: &BlockCandidate<P>) -> Option<Duration>
+    {
+        let public = self.public_key()?;
+        let state = self.state_after(candidate.height - 1, &candidate.previous_hash)?;
+
+        if state.leader(self.slot(candidate.timestamp)) != Some(&public)
+        {
+            return None;
+        }
+
+        Some(Duration::from_secs((candidate.timestamp - Utc::now().timestamp()).max(0) as u64))
+    }
+
+    fn seal(&self, candidate: &BlockCandidate<P>, nonce: u64) -> Option<Block<P>>
+    {
+        let key = self.key.as_ref()?;
+
+        let hash = candidate.hash(nonce);
+        let signature = BlockSignature
+        {
+            signer: hex::encode(key.public().to_bytes()),
+            signature: hex::encode(key.sign(hash.as_bytes())),
+        };
+
+        let mut block = candidate.clone().into_block(hash, nonce);
+        block.signature = Some(signature);
+
+        Some(block)
+    }
+
+    fn verify_seal(&self, block: &Block<P>, parent: &Block<P>) -> Result<(), Error>
+    {
+        let state = self.state_after(parent.height, &parent.hash).ok_or(Error::UnauthorizedSigner)?;
+
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        let slot = self.slot(block.timestamp);
+
+        if parent.height > 0 && slot <= self.slot(parent.timestamp)
+        {
+            return Err(Error::InvalidSlot);
+        }
+
+        let signature = block.signature.as_ref().ok_or(Error::InvalidSignature)?;
+
+        if state.leader(slot) != Some(&signature.signer)
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !verify_signature(&signature.signer, block.hash.as_bytes(), &signature.signature)
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        if block.records.iter().any(|record| !matches!(record, ConsensusRecord::Vote { .. }))
+        {
+            return Err(Error::UnsupportedRecord);
+        }
+
+        //A leader can't sign for a slot that hasn't come up yet, give or take a slot of clock drift.
+        if (slot * self.slot_time) as i64 > Utc::now().timestamp() + self.slot_time as i64
+        {
+            return Err(Error::TimestampTooFarAhead);
+        }
+
+        Ok(())
+    }
+
+    //Without the parent the slot's leader is unknown, but the signer has to be a validator we know of and the signature has to hold.
+    fn verify_header(&self, block: &Block<P>) -> Result<(), Error>
+    {
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        let signature = block.signature.as_ref().ok_or(Error::InvalidSignature)?;
+
+        if !self.is_known_validator(&signature.signer)
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !verify_signature(&signature.signer, block.hash.as_bytes(), &signature.signature)
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    fn accept(&self, block: &Block<P>, parent: &Block<P>)
+    {
+        if let Some(state) = self.state_after(parent.height, &parent.hash)
+        {
+            self.states.lock().unwrap().insert(block.hash.clone(), (block.height, state.apply(block)));
+        }
+    }
+
+    fn prune(&self, height: u64)
+    {
+        self.states.lock().unwrap().retain(|_, (block_height, _)| *block_height >= height);
+    }
+
+    fn weight(&self, _block: &Block<P>) -> u128
+    {
+        1
+    }
+
+    fn submit_record(&self, record: ConsensusRecord) -> Result<(), Error>
+    {
+        if self.key.is_none()
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !matches!(record, ConsensusRecord::Vote { .. })
+        {
+            return Err(Error::UnsupportedRecord);
+        }
+
+        let mut proposals = self.proposals.lock().unwrap();
+
+        if !proposals.contains(&record)
+        {
+            proposals.push(record);
+        }
+
+        Ok(())
+    }
+
+    fn validators(&self, tip: &Block<P>) -> Vec<String>
+    {
+        self.state_after(tip.height, &tip.hash).map(|state| state.validators).unwrap_or_default()
+    }
+}
+
+//Keys are plain hex, the secret is the 32 byte ed25519 seed.
+pub fn parse_key(secret: &str) -> Result<Keypair, Error>
+{
+    let bytes = hex::decode(secret).map_err(|_| Error::InvalidArgument(String::from("Validator key must be hex")))?;
+    let secret = SecretKey::try_from_bytes(bytes).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+
+    Ok(Keypair::from(secret))
+}
+
+pub fn check_public_key(public: &str) -> Result<(), Error>
+{
+    hex::decode(public).ok().and_then(|bytes| PublicKey::try_from_bytes(&bytes).ok()).ok_or_else(|| Error::InvalidArgument(format!("{public} is not a validator public key")))?;
+
+    Ok(())
+}
+
+pub fn verify_signature(signer: &str, message: &[u8], signature: &str) -> bool
+{
+    let Some(public) = hex::decode(signer).ok().and_then(|bytes| PublicKey::try_from_bytes(&bytes).ok()) else { return false };
+    let Ok(signature) = hex::decode(signature) else { return false };
+
+    public.verify(message, &signature)
+}
+
+//Returns (secret, public).
+pub fn generate_key() -> (String, String)
+{
+    let key = Keypair::generate();
+
+    (hex::encode(key.secret().as_ref()), hex::encode(key.public().to_bytes()))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::BlockState;
+    use crate::consensus::BlockSignature;
+
+    fn state(validators: &[&str]) -> AuthorityState
+    {
+        AuthorityState { validators: validators.iter().map(|validator| validator.to_string()).collect(), tally: HashMap::new() }
+    }
+
+    //apply only looks at who signed and what was voted, the signature itself is checked by verify_seal.
+    fn votes(signer: &str, votes: &[(&str, bool)]) -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        let mut block = chain.blocks.remove(0);
+        block.signature = Some(BlockSignature { signer: signer.to_owned(), signature: String::new() });
+        block.records = votes.iter().map(|(validator, add)| ConsensusRecord::Vote { validator: validator.to_string(), add: *add }).collect();
+        block
+    }
+
+    #[test]
+    fn a_strict_majority_changes_the_set()
+    {
+        let mut state = state(&["a", "b", "c", "d"]);
+
+        state = state.apply(&votes("a", &[("e", true)]));
+        state = state.apply(&votes("b", &[("e", true)]));
+        assert!(!state.validators.contains(&String::from("e")));
+
+        //Voting twice doesn't count twice.
+        state = state.apply(&votes("b", &[("e", true)]));
+        assert!(!state.validators.contains(&String::from("e")));
+
+        state = state.apply(&votes("c", &[("e", true)]));
+        assert!(state.validators.contains(&String::from("e")));
+        assert!(state.tally.is_empty());
+    }
+
+    #[test]
+    fn votes_leave_with_the_validator_that_cast_them()
+    {
+        let mut state = state(&["a", "b", "c", "d"]);
+
+        state = state.apply(&votes("d", &[("e", true)]));
+
+        for signer in ["a", "b", "c"]
+        {
+            state = state.apply(&votes(signer, &[("d", false)]));
+        }
+
+        assert_eq!(state.validators, vec!["a", "b", "c"]);
+
+        //d's vote for e is gone, so a alone is one of three and not a majority.
+        state = state.apply(&votes("a", &[("e", true)]));
+        assert!(!state.validators.contains(&String::from("e")));
+
+        state = state.apply(&votes("b", &[("e", true)]));
+        assert!(state.validators.contains(&String::from("e")));
+    }
+
+    #[test]
+    fn outsiders_cannot_vote()
+    {
+        let state = state(&["a", "b"]).apply(&votes("x", &[("x", true)]));
+
+        assert_eq!(state.validators, vec!["a", "b"]);
+        assert!(state.tally.is_empty());
+    }
+
+    //Two validators taking turns every second, each with its own engine, and an observer that only checks their blocks.
+    fn network() -> (ProofOfAuthority, ProofOfAuthority, ProofOfAuthority)
+    {
+        let (first, second) = (Keypair::generate(), Keypair::generate());
+        let validators = vec![hex::encode(first.public().to_bytes()), hex::encode(second.public().to_bytes())];
+
+        (
+            ProofOfAuthority::new(validators.clone(), 1, Some(first)),
+            ProofOfAuthority::new(validators.clone(), 1, Some(second)),
+            ProofOfAuthority::new(validators, 1, None),
+        )
+    }
+
+    //Backdated so the slots after it have come up already.
+    fn genesis() -> Block
+    {
+        let mut genesis = votes("", &[]);
+        genesis.signature = None;
+        genesis.timestamp -= 60;
+        genesis
+    }
+
+    //The engine whose turn comes first seals, the other one is handed back too.
+    fn propose<'a>(engines: [&'a ProofOfAuthority; 2], parent: &Block) -> (Block, &'a ProofOfAuthority, &'a ProofOfAuthority)
+    {
+        let candidates = engines.map(|engine| engine.prepare(parent, String::from("poa"), Vec::new()));
+        let first = if candidates[0].timestamp <= candidates[1].timestamp { 0 } else { 1 };
+
+        let engine = engines[first];
+        assert!(engine.ready_in(&candidates[first]).is_some());
+
+        (engine.seal(&candidates[first], 0).unwrap(), engine, engines[1 - first])
+    }
+
+    #[test]
+    fn the_slot_leader_seals_and_everyone_accepts()
+    {
+        let (first, second, observer) = network();
+        let genesis = genesis();
+
+        let (block, _, _) = propose([&first, &second], &genesis);
+
+        observer.verify_seal(&block, &genesis).unwrap();
+        observer.verify_header(&block).unwrap();
+    }
+
+    #[test]
+    fn a_validator_out_of_turn_is_refused()
+    {
+        let (first, second, observer) = network();
+        let genesis = genesis();
+
+        let (_, leader, other) = propose([&first, &second], &genesis);
+
+        //The leader's slot, signed by the validator whose turn it isn't.
+        let candidate = leader.prepare(&genesis, String::from("poa"), Vec::new());
+        assert!(other.ready_in(&candidate).is_none());
+
+        let block = other.seal(&candidate, 0).unwrap();
+        assert!(matches!(observer.verify_seal(&block, &genesis), Err(Error::UnauthorizedSigner)));
+
+        //Without the parent the slot is unknown, a known validator's signature is as far as it goes.
+        observer.verify_header(&block).unwrap();
+    }
+
+    #[test]
+    fn outsiders_and_forged_signatures_are_refused()
+    {
+        let (first, second, observer) = network();
+        let genesis = genesis();
+
+        let (block, _, _) = propose([&first, &second], &genesis);
+
+        let outsider = ProofOfAuthority::new(Vec::new(), 1, Some(Keypair::generate()));
+        let mut candidate = first.prepare(&genesis, String::from("poa"), Vec::new());
+        candidate.timestamp = block.timestamp;
+        let forged = outsider.seal(&candidate, 0).unwrap();
+
+        assert!(matches!(observer.verify_seal(&forged, &genesis), Err(Error::UnauthorizedSigner)));
+        assert!(matches!(observer.verify_header(&forged), Err(Error::UnauthorizedSigner)));
+
+        let mut copied = forged;
+        copied.signature = block.signature.clone();
+        assert!(matches!(observer.verify_seal(&copied, &genesis), Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn every_block_needs_a_later_slot_than_its_parent()
+    {
+        let (first, second, observer) = network();
+        let genesis = genesis();
+
+        let (block, leader, _) = propose([&first, &second], &genesis);
+        observer.verify_seal(&block, &genesis).unwrap();
+
+        for engine in [&first, &second, &observer]
+        {
+            engine.accept(&block, &genesis);
+        }
+
+        let mut candidate = leader.prepare(&block, String::from("poa"), Vec::new());
+        candidate.timestamp = block.timestamp;
+        let same_slot = leader.seal(&candidate, 0).unwrap();
+
+        assert!(matches!(observer.verify_seal(&same_slot, &block), Err(Error::InvalidSlot)));
+
+        let (next, _, _) = propose([&first, &second], &block);
+        observer.verify_seal(&next, &block).unwrap();
+    }
+}
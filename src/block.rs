@@ -1,40 +1,200 @@
+use crate::clock::median;
+use crate::consensus::{ BlockSignature, ConsensusEngine, ConsensusRecord, ProofOfWork, records_commitment };
 use crate::error::Error;
+use crate::payload::Payload;
+use crate::merkle::{ self, MerkleProof, TransactionProof };
+use crate::transaction::{ Transaction, MAX_BLOCK_TRANSACTIONS, transactions_root };
+use std::collections::{ BTreeMap, HashMap, HashSet };
 use std::fmt;
 use serde::{ Serialize, Deserialize };
 use chrono::Utc;
 use sha2::{ Sha256, Digest };
 use uuid::Uuid;
-use tokio::sync::mpsc;
-use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
-use rand::prelude::*;
 use std::fs::{ write, File };
-
-const DEFAULT_PREFIX: &str = "6767";
+use std::sync::Arc;
+
+pub const DEFAULT_PREFIX: &str = "6767";
+pub const GENESIS_HASH: &str = "676700000000000000000000000000000000000000000000000000000000000";
+//Hashes every node agrees on ahead of time, a chain that disagrees at any of these heights is rejected outright.
+pub const CHECKPOINTS: &[(u64, &str)] = &[(0, GENESIS_HASH)];
+//Consensus limits, a block over either is invalid no matter who sealed it. The size is of the JSON encoding.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+pub const MAX_BLOCK_RECORDS: usize = 64;
+//A block can't be older than the median of the blocks before it, nor further ahead of network time than the drift.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const MAX_FUTURE_DRIFT: i64 = 2 * 60;
 type BlockResult<T> = Result<T, Error>;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Block
+pub struct Block<P = String>
 {
     pub index: Uuid,
     pub timestamp: i64,
-    pub data: String,
+    pub data: P,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub merkle_root: String,
     pub previous_hash: String,
     pub hash: String,
     pub nonce: u64,
+    #[serde(default)]
+    pub extra_nonce: u64,
     pub height: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<ConsensusRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<BlockSignature>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct BlockCandidate
+impl<P: Payload> Block<P>
+{
+    //The hash this block should have given its contents, seals are checked against it.
+    pub fn recalculate_hash(&self) -> String
+    {
+        calculate_hash(self.index, self.timestamp, &[&self.data.commitment(), &self.merkle_root, &records_commitment(&self.records), &self.previous_hash], self.extra_nonce, self.nonce)
+    }
+
+    //Everything but the transactions, which is all a light client keeps.
+    pub fn header(&self) -> BlockHeader<P>
+    {
+        BlockHeader
+        {
+            index: self.index,
+            timestamp: self.timestamp,
+            data: self.data.clone(),
+            merkle_root: self.merkle_root.clone(),
+            previous_hash: self.previous_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            extra_nonce: self.extra_nonce,
+            height: self.height,
+            records: self.records.clone(),
+            signature: self.signature.clone(),
+        }
+    }
+
+    //Proves the transaction is part of this block against its merkle_root.
+    pub fn transaction_proof(&self, id: &str) -> Option<MerkleProof>
+    {
+        let ids: Vec<String> = self.transactions.iter().map(|transaction| transaction.id.clone()).collect();
+        let index = ids.iter().position(|leaf| leaf == id)?;
+
+        merkle::prove(&ids, index)
+    }
+}
+
+//A block without its body, the merkle_root still commits to the transactions so the hash and seal can be checked.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockHeader<P = String>
 {
     pub index: Uuid,
     pub timestamp: i64,
-    pub data: String,
+    pub data: P,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub merkle_root: String,
     pub previous_hash: String,
+    pub hash: String,
+    pub nonce: u64,
+    #[serde(default)]
+    pub extra_nonce: u64,
     pub height: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<ConsensusRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<BlockSignature>,
+}
+
+impl<P> BlockHeader<P>
+{
+    //A block with an empty body, only a light chain accepts it.
+    pub fn into_block(self) -> Block<P>
+    {
+        Block
+        {
+            index: self.index,
+            timestamp: self.timestamp,
+            data: self.data,
+            transactions: Vec::new(),
+            merkle_root: self.merkle_root,
+            previous_hash: self.previous_hash,
+            hash: self.hash,
+            nonce: self.nonce,
+            extra_nonce: self.extra_nonce,
+            height: self.height,
+            records: self.records,
+            signature: self.signature,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockCandidate<P = String>
+{
+    pub index: Uuid,
+    pub timestamp: i64,
+    pub data: P,
+    pub transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub merkle_root: String,
+    pub previous_hash: String,
+    pub extra_nonce: u64,
+    pub height: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<ConsensusRecord>,
+}
+
+impl<P: Payload> BlockCandidate<P>
+{
+    pub fn new(parent: &Block<P>, data: P, transactions: Vec<Transaction>) -> Self
+    {
+        Self
+        {
+            index: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp().max(parent.timestamp),
+            data,
+            merkle_root: transactions_root(&transactions),
+            transactions,
+            previous_hash: parent.hash.clone(),
+            extra_nonce: 0,
+            height: parent.height + 1,
+            records: Vec::new(),
+        }
+    }
+
+    //Keeps the root in step with the transactions, so hashing a nonce does not rebuild the tree.
+    pub fn set_transactions(&mut self, transactions: Vec<Transaction>)
+    {
+        self.merkle_root = transactions_root(&transactions);
+        self.transactions = transactions;
+    }
+
+    pub fn hash(&self, nonce: u64) -> String
+    {
+        calculate_hash(self.index, self.timestamp, &[&self.data.commitment(), &self.merkle_root, &records_commitment(&self.records), &self.previous_hash], self.extra_nonce, nonce)
+    }
+
+    pub fn into_block(self, hash: String, nonce: u64) -> Block<P>
+    {
+        Block
+        {
+            index: self.index,
+            timestamp: self.timestamp,
+            data: self.data,
+            transactions: self.transactions,
+            merkle_root: self.merkle_root,
+            previous_hash: self.previous_hash,
+            hash,
+            nonce,
+            extra_nonce: self.extra_nonce,
+            height: self.height,
+            records: self.records,
+            signature: None,
+        }
+    }
 }
 
-impl std::fmt::Display for Block
+impl<P: fmt::Display> fmt::Display for Block<P>
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
@@ -43,19 +203,180 @@ impl std::fmt::Display for Block
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct BlockState
+#[serde(bound = "")]
+pub struct BlockState<P: Payload = String>
+{
+    pub blocks: Vec<Block<P>>,
+    //Blocks up to this height can no longer be reorganized away.
+    #[serde(default)]
+    pub finalized: u64,
+    //A light chain only holds headers, its blocks have no transactions to check against their merkle_root.
+    #[serde(default)]
+    pub light: bool,
+    #[serde(skip, default = "default_engine")]
+    engine: Arc<dyn ConsensusEngine<P>>,
+    #[serde(skip, default = "default_checkpoints")]
+    checkpoints: BTreeMap<u64, String>,
+    //Seals, sizes and transaction signatures of blocks up to this height are taken as already checked, once the
+    //header chain we fetched for it links up to the assumed valid hash.
+    #[serde(skip)]
+    assume_valid: u64,
+    //Hashes by height of that header chain, complete once it reaches assume_valid.
+    #[serde(skip)]
+    assumed: Vec<String>,
+    #[serde(skip)]
+    time_offset: i64,
+    //Height of every confirmed transaction by id, rebuilt whenever the chain is loaded.
+    #[serde(skip)]
+    confirmed: HashMap<String, u64>,
+}
+
+fn default_engine<P: Payload>() -> Arc<dyn ConsensusEngine<P>>
 {
-    pub blocks: Vec<Block>,
+    Arc::new(ProofOfWork::default())
 }
 
-impl BlockState
+fn default_checkpoints() -> BTreeMap<u64, String>
+{
+    CHECKPOINTS.iter().map(|(height, hash)| (*height, String::from(*hash))).collect()
+}
+
+impl<P: Payload> Default for BlockState<P>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl<P: Payload> BlockState<P>
 {
     pub fn new () -> Self
     {
         Self
         {
-            blocks: Vec::new()
+            blocks: Vec::new(),
+            finalized: 0,
+            light: false,
+            engine: default_engine(),
+            checkpoints: default_checkpoints(),
+            assume_valid: 0,
+            assumed: Vec::new(),
+            time_offset: 0,
+            confirmed: HashMap::new(),
+        }
+    }
+
+    pub fn engine(&self) -> Arc<dyn ConsensusEngine<P>>
+    {
+        self.engine.clone()
+    }
+
+    pub fn set_engine(&mut self, engine: Arc<dyn ConsensusEngine<P>>)
+    {
+        engine.replay(&self.blocks);
+        self.engine = engine;
+        self.prune_engine();
+    }
+
+    pub fn checkpoints(&self) -> &BTreeMap<u64, String>
+    {
+        &self.checkpoints
+    }
+
+    //Pins a hash at a height on top of the built-in checkpoints, the blocks we already hold have to agree with it.
+    pub fn add_checkpoint(&mut self, height: u64, hash: String) -> BlockResult<()>
+    {
+        let held = self.blocks.get(height as usize).map(|block| &block.hash);
+
+        if held.is_some_and(|held| *held != hash) || self.checkpoints.get(&height).is_some_and(|pinned| *pinned != hash)
+        {
+            return Err(Error::CheckpointMismatch);
+        }
+
+        self.checkpoints.insert(height, hash);
+        self.prune_engine();
+        Ok(())
+    }
+
+    //Skipping checks below a block is only safe on the branch that leads to it, so the block becomes a checkpoint too.
+    pub fn set_assume_valid(&mut self, height: u64, hash: String) -> BlockResult<()>
+    {
+        self.add_checkpoint(height, hash)?;
+        self.assume_valid = height;
+        Ok(())
+    }
+
+    //The height to fetch headers from while the header chain to the assumed valid block is incomplete and our blocks
+    //haven't got past it yet.
+    pub fn assumed_headers_from(&self) -> Option<u64>
+    {
+        let next = self.assumed.len().max(1) as u64;
+
+        (next <= self.assume_valid && (self.blocks.len() as u64) <= self.assume_valid).then_some(next)
+    }
+
+    //Extends the header chain towards the assumed valid block. Only hashes are checked: every header has to hash to
+    //what it claims and link to the one before, and the chain has to end on the assumed valid hash, which commits to
+    //everything below it. Returns whether the chain is complete.
+    pub fn add_assumed_headers(&mut self, headers: Vec<BlockHeader<P>>) -> BlockResult<bool>
+    {
+        if self.assumed.is_empty()
+        {
+            let genesis = self.blocks.first().ok_or(Error::OutOfBounds)?;
+            self.assumed.push(genesis.hash.clone());
         }
+
+        let next = self.assumed.len() as u64;
+
+        for header in headers.into_iter().skip_while(|header| header.height < next)
+        {
+            if header.height > self.assume_valid
+            {
+                break;
+            }
+
+            let block = header.into_block();
+
+            if block.height != self.assumed.len() as u64 || Some(&block.previous_hash) != self.assumed.last() || block.recalculate_hash() != block.hash
+            {
+                return Err(Error::InvalidHash);
+            }
+
+            if let Err(e) = self.check_checkpoint(&block)
+            {
+                self.assumed.truncate(1);
+                return Err(e);
+            }
+
+            self.assumed.push(block.hash);
+        }
+
+        Ok(self.assumed.len() as u64 > self.assume_valid)
+    }
+
+    //On the header chain leading to the assumed valid block, and its contents still hash to its place there.
+    fn is_assumed(&self, block: &Block<P>) -> bool
+    {
+        self.assumed.len() as u64 > self.assume_valid
+            && self.assumed.get(block.height as usize) == Some(&block.hash)
+            && block.recalculate_hash() == block.hash
+    }
+
+    pub fn time_offset(&self) -> i64
+    {
+        self.time_offset
+    }
+
+    pub fn set_time_offset(&mut self, offset: i64)
+    {
+        self.time_offset = offset;
+    }
+
+    //Our clock moved by how far off it is from our peers' clocks.
+    pub fn network_time(&self) -> i64
+    {
+        Utc::now().timestamp() + self.time_offset
     }
 
     pub fn save_to_file(&self, path: &str) -> BlockResult<()>
@@ -71,7 +392,12 @@ impl BlockState
 
         if let Ok(serialized) = std::fs::read_to_string(path)
         {
-            let deserialized: BlockState = serde_json::from_str(&serialized)?;
+            let mut deserialized: BlockState<P> = serde_json::from_str(&serialized)?;
+
+            for block in &deserialized.blocks
+            {
+                deserialized.confirmed.extend(confirmed_in(block));
+            }
 
             Ok(deserialized)
             
@@ -90,18 +416,25 @@ impl BlockState
         {
             index: Uuid::new_v4(),
             timestamp: Utc::now().timestamp(),
-            data: String::from("DAPProptech is the way"),
+            data: P::genesis(),
+            transactions: Vec::new(),
+            merkle_root: String::new(),
             previous_hash: String::from("0"),
-            hash: DEFAULT_PREFIX.to_owned() + "00000000000000000000000000000000000000000000000000000000000",
+            hash: String::from(GENESIS_HASH),
             nonce: 3694,
+            extra_nonce: 0,
             height: 0,
+            records: Vec::new(),
+            signature: None,
         };
     
         self.blocks.push(genesis_block)
     }
 
-    pub fn add_block(&mut self, block: Block) -> BlockResult<()>
+    pub fn add_block(&mut self, block: Block<P>) -> BlockResult<()>
     {
+        self.engine.observe(&block);
+
         if self.blocks.last().is_none() 
         {
             return Err(Error::OutOfBounds);
@@ -112,132 +445,467 @@ impl BlockState
             return Err(Error::ChainTooShort);
         }
 
-        let previous_hash = &self.blocks.last().unwrap().hash;
+        self.compare_hash(&block.previous_hash)?;
+        self.check_checkpoint(&block)?;
 
-        self.compare_hash(previous_hash)?;
+        //The seal goes first, so a block held back for being ahead of our clock is at least one its signer really made.
+        self.validate_block(&block, self.blocks.last().unwrap())?;
+        self.check_duplicates(&block, block.height, &mut HashSet::new())?;
+        self.check_timestamp(&block, &recent_timestamps(&self.blocks))?;
+        self.engine.accept(&block, self.blocks.last().unwrap());
 
-        check_prefix(block.index, &block.data, &block.hash, &block.previous_hash, block.nonce)?;
-        println!("data: {}", &block.data);
+        println!("data: {}", block.data.commitment());
+        self.confirmed.extend(confirmed_in(&block));
         self.blocks.push(block);
+
+        if self.blocks.len() as u64 > self.assume_valid
+        {
+            self.assumed = Vec::new();
+        }
+
         Ok(())
     }
 
-    pub fn compare_hash(&self, hash: &str) -> BlockResult<()>
+    //Swaps our blocks from the fork point onwards for a longer branch, handing back the blocks that were dropped.
+    pub fn reorganize(&mut self, branch: Vec<Block<P>>) -> BlockResult<Vec<Block<P>>>
     {
-        if self.blocks.last().unwrap().hash != hash
+        let fork_height = branch.first().ok_or(Error::OutOfBounds)?.height as usize;
+
+        if fork_height == 0 || fork_height > self.blocks.len()
         {
-            return Err(Error::InvalidHash);
+            return Err(Error::OutOfBounds);
         }
 
-        Ok(())
+        if fork_height as u64 <= self.finalized
+        {
+            return Err(Error::FinalizedBlock);
+        }
+
+        //A branch forking below a checkpoint we hold would swap out the checkpointed block.
+        if self.checkpoints.range(fork_height as u64..).next().is_some_and(|(height, _)| (*height as usize) < self.blocks.len())
+        {
+            return Err(Error::CheckpointMismatch);
+        }
+
+        let branch_weight: u128 = branch.iter().map(|block| self.engine.weight(block)).sum();
+        let current_weight: u128 = self.blocks[fork_height..].iter().map(|block| self.engine.weight(block)).sum();
+
+        if branch_weight <= current_weight
+        {
+            return Err(Error::ChainTooShort);
+        }
+
+        let mut parent = &self.blocks[fork_height - 1];
+        let mut window = recent_timestamps(&self.blocks[..fork_height]);
+        let mut on_branch = HashSet::new();
+
+        for block in &branch
+        {
+            if block.previous_hash != parent.hash || block.height != parent.height + 1
+            {
+                return Err(Error::InvalidHash);
+            }
+
+            self.check_checkpoint(block)?;
+            self.validate_block(block, parent)?;
+            self.check_duplicates(block, fork_height as u64, &mut on_branch)?;
+            self.check_timestamp(block, &window)?;
+            self.engine.accept(block, parent);
+
+            window.insert(0, block.timestamp);
+            window.truncate(MEDIAN_TIME_SPAN);
+            parent = block;
+        }
+
+        let removed = self.blocks.split_off(fork_height);
+
+        for transaction in removed.iter().flat_map(|block| block.transactions.iter())
+        {
+            self.confirmed.remove(&transaction.id);
+        }
+
+        for block in &branch
+        {
+            self.confirmed.extend(confirmed_in(block));
+        }
+
+        self.blocks.extend(branch);
+
+        Ok(removed)
     }
-}
 
-pub fn check_prefix(index: Uuid, data: &str, hash: &str, previous_hash: &str, nonce: u64) -> BlockResult<()>
-{
-    if hash.starts_with(DEFAULT_PREFIX)
+    pub fn finalize(&mut self, height: u64) -> BlockResult<()>
     {
-        let recalc_hash = calculate_hash(index, data, previous_hash, nonce);
-        
-        if recalc_hash != hash
+        if height as usize >= self.blocks.len()
         {
-            return Err(Error::InvalidHash);
+            return Err(Error::OutOfBounds);
         }
 
+        self.finalized = self.finalized.max(height);
+        self.prune_engine();
         Ok(())
     }
-    else
+
+    //Nothing below the finalized block or the highest checkpoint we hold can be reorganized away, so the engine
+    //only has to keep what it tracks from there up.
+    fn prune_engine(&self)
     {
-        Err(Error::InvalidPrefix)
+        let tip = self.blocks.len() as u64;
+        let checkpointed = self.checkpoints.range(..tip).next_back().map_or(0, |(height, _)| *height);
+
+        self.engine.prune(self.finalized.max(checkpointed));
     }
-}
 
-pub fn mine_block(block_candidate: BlockCandidate, stop_signal: Arc<AtomicBool>) -> Option<Block>
-{
-    println!("Mining block...");
-    let mut nonce = 0;
+    //What a gossiped block shows before its transactions are filled in: its limits, and its seal against the parent
+    //when we hold that. OutOfBounds means we don't, and nothing more than verify_header can be said yet.
+    pub fn check_header(&self, header: &BlockHeader<P>, transactions: usize) -> BlockResult<()>
+    {
+        if transactions > MAX_BLOCK_TRANSACTIONS || header.records.len() > MAX_BLOCK_RECORDS
+        {
+            return Err(Error::BlockTooLarge);
+        }
+
+        let block = header.clone().into_block();
+        block.data.validate()?;
 
-    loop
+        let parent = block.height.checked_sub(1)
+            .and_then(|height| self.blocks.get(height as usize))
+            .filter(|parent| parent.hash == block.previous_hash);
+
+        match parent
+        {
+            Some(parent) => self.engine.verify_seal(&block, parent),
+            None =>
+            {
+                self.engine.verify_header(&block)?;
+                Err(Error::OutOfBounds)
+            },
+        }
+    }
+
+    //A validly sealed block on a parent we hold, beaten to its height by the block we have there.
+    pub fn is_stale(&self, block: &Block<P>) -> bool
     {
-        if nonce % 10000 == 0 && stop_signal.load(Ordering::SeqCst)
+        let height = block.height as usize;
+
+        match (height.checked_sub(1).and_then(|parent| self.blocks.get(parent)), self.blocks.get(height))
         {
-            println!("Nonce: {nonce}");
-            return None;
-            
+            (Some(parent), Some(winner)) => parent.hash == block.previous_hash && winner.hash != block.hash && self.engine.verify_seal(block, parent).is_ok(),
+            _ => false,
         }
+    }
 
-        let hash = calculate_hash(block_candidate.index, &block_candidate.data, &block_candidate.previous_hash, nonce);
-        if hash.starts_with(DEFAULT_PREFIX)
+    pub fn find_block(&self, hash: &str) -> Option<&Block<P>>
+    {
+        self.blocks.iter().find(|block| block.hash == hash)
+    }
+
+    pub fn is_confirmed(&self, id: &str) -> bool
+    {
+        self.confirmed.contains_key(id)
+    }
+
+    pub fn find_transaction(&self, id: &str) -> Option<(&Block<P>, &Transaction)>
+    {
+        let block = self.blocks.get(*self.confirmed.get(id)? as usize)?;
+
+        block.transactions.iter().find(|transaction| transaction.id == id).map(|transaction| (block, transaction))
+    }
+
+    pub fn transaction_proof(&self, id: &str) -> Option<TransactionProof>
+    {
+        let (block, _) = self.find_transaction(id)?;
+
+        Some(TransactionProof
         {
-            println!("
-            Nonce: {nonce},
-            Hash: {hash},
-            ");
+            block_hash: block.hash.clone(),
+            height: block.height,
+            merkle_root: block.merkle_root.clone(),
+            proof: block.transaction_proof(id)?,
+        })
+    }
 
-            return Some(Block
-            {
-                index: block_candidate.index,
-                timestamp: block_candidate.timestamp,
-                data: block_candidate.data,
-                previous_hash: block_candidate.previous_hash.to_string(),
-                hash,
-                nonce,
-                height: block_candidate.height,
-            });
-        }            
-        nonce += 1;
+    //Checks a proof from another node against the header we hold at its height.
+    pub fn verify_proof(&self, id: &str, proof: &TransactionProof) -> BlockResult<()>
+    {
+        let block = self.blocks.get(proof.height as usize).filter(|block| block.hash == proof.block_hash).ok_or(Error::InvalidProof)?;
+
+        if proof.proof.leaf != id || proof.merkle_root != block.merkle_root || !proof.proof.verify(&block.merkle_root)
+        {
+            return Err(Error::InvalidProof);
+        }
+
+        Ok(())
     }
-}
 
-pub fn mine_trigger(last_block: Block, tx: mpsc::Sender<Block>, stop_signal: Arc<AtomicBool>)
-{
-    let miner_tx = tx.clone();
+    pub fn compare_hash(&self, hash: &str) -> BlockResult<()>
+    {
+        if self.blocks.last().unwrap().hash != hash
+        {
+            return Err(Error::InvalidHash);
+        }
 
-    tokio::task::spawn_blocking(move || 
+        Ok(())
+    }
+
+    fn check_checkpoint(&self, block: &Block<P>) -> BlockResult<()>
     {
-            let candidate = BlockCandidate
-            {
-                index: Uuid::new_v4(),
-                timestamp: Utc::now().timestamp(),
-                data: generate_random_data(),
-                previous_hash: last_block.hash,
-                height: last_block.height + 1,
-            };
-            
-            if let Some(mined) = mine_block(candidate, stop_signal)
+        match self.checkpoints.get(&block.height)
+        {
+            Some(hash) if *hash != block.hash => Err(Error::CheckpointMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_timestamp(&self, block: &Block<P>, window: &[i64]) -> BlockResult<()>
+    {
+        if median(window.to_vec()).is_some_and(|median| block.timestamp < median)
+        {
+            return Err(Error::TimestampTooOld);
+        }
+
+        if block.timestamp > self.network_time() + MAX_FUTURE_DRIFT
+        {
+            return Err(Error::TimestampTooFarAhead);
+        }
+
+        Ok(())
+    }
+
+    //A transaction can only be confirmed once: not twice in the block, not again on the branch it extends (seen holds the
+    //branch's own ids so far) and not again once it's confirmed below height.
+    fn check_duplicates(&self, block: &Block<P>, height: u64, seen: &mut HashSet<String>) -> BlockResult<()>
+    {
+        for transaction in &block.transactions
+        {
+            if self.confirmed.get(&transaction.id).is_some_and(|confirmed| *confirmed < height) || !seen.insert(transaction.id.clone())
             {
-                _ = miner_tx.blocking_send(mined);
+                return Err(Error::DuplicateTransaction);
             }
-            else
+        }
+
+        Ok(())
+    }
+
+    fn validate_block(&self, block: &Block<P>, parent: &Block<P>) -> BlockResult<()>
+    {
+        let assumed = self.is_assumed(block);
+
+        if block.transactions.len() > MAX_BLOCK_TRANSACTIONS || block.records.len() > MAX_BLOCK_RECORDS
+        {
+            return Err(Error::BlockTooLarge);
+        }
+
+        if !assumed
+        {
+            if serde_json::to_vec(block)?.len() > MAX_BLOCK_SIZE
             {
-                println!("No block has been mined!");
+                return Err(Error::BlockTooLarge);
             }
-    });
+
+            self.engine.verify_seal(block, parent)?;
+        }
+
+        block.data.validate()?;
+
+        if !self.light && block.merkle_root != transactions_root(&block.transactions)
+        {
+            return Err(Error::InvalidMerkleRoot);
+        }
+
+        if assumed
+        {
+            return Ok(());
+        }
+
+        for transaction in &block.transactions
+        {
+            transaction.verify()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn confirmed_in<P: Payload>(block: &Block<P>) -> impl Iterator<Item = (String, u64)> + '_
+{
+    block.transactions.iter().map(|transaction| (transaction.id.clone(), block.height))
 }
 
-fn calculate_hash(index: Uuid, data: &str, previous_hash: &str, nonce: u64) -> String
+//Newest first. Genesis is left out, every node stamps its own with the time it first started.
+fn recent_timestamps<P: Payload>(blocks: &[Block<P>]) -> Vec<i64>
+{
+    blocks.iter().skip(1).rev().take(MEDIAN_TIME_SPAN).map(|block| block.timestamp).collect()
+}
+
+//Fields are the payload commitment, merkle root, records commitment and previous hash. Like transaction ids each one is length prefixed
+//and the numbers are fixed width, so no two different headers feed the hasher the same bytes.
+pub fn calculate_hash(index: Uuid, timestamp: i64, fields: &[&str], extra_nonce: u64, nonce: u64) -> String
 {
     let mut hasher = Sha256::new();
 
-    let val = format!("{index}{data}{previous_hash}{nonce}");
-    hasher.update(val);
-    let hash = hasher.finalize();
+    hasher.update(index.as_bytes());
+    hasher.update(timestamp.to_le_bytes());
 
-    hex::encode(hash)
+    for field in fields
+    {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field.as_bytes());
+    }
+
+    hasher.update(extra_nonce.to_le_bytes());
+    hasher.update(nonce.to_le_bytes());
+
+    hex::encode(hasher.finalize())
 }
 
-fn generate_random_data() -> String
-{   
-    let mut rng = rand::rng();
-    
-    let mut word = String::with_capacity(20);
+#[cfg(test)]
+mod tests
+{
+    use super::*;
 
-    for _ in 0..19
+    //Any hash seals a block, so tests don't have to mine.
+    fn chain() -> BlockState
     {
-        word.push(rng.sample(rand::distr::Alphanumeric) as char );
+        let mut chain = BlockState::new();
+        chain.set_engine(Arc::new(ProofOfWork { prefix: String::new() }));
+        chain.create_genesis_block();
+        chain
+    }
+
+    fn child(parent: &Block, transactions: Vec<Transaction>) -> Block
+    {
+        let candidate = BlockCandidate::new(parent, String::from("child"), transactions);
+        let hash = candidate.hash(0);
+
+        candidate.into_block(hash, 0)
     }
-    
-    word
-}
 
+    fn transaction(amount: u64) -> Transaction
+    {
+        Transaction::new(String::from("alice"), String::from("bob"), amount)
+    }
+
+    fn stamped(parent: &Block, timestamp: i64) -> Block
+    {
+        let mut candidate = BlockCandidate::new(parent, String::from("stamped"), Vec::new());
+        candidate.timestamp = timestamp;
+        let hash = candidate.hash(0);
+
+        candidate.into_block(hash, 0)
+    }
+
+    //Blocks an hour in the past, ten seconds apart from one another.
+    fn history(count: i64) -> BlockState
+    {
+        let mut chain = chain();
+        let start = Utc::now().timestamp() - 3600;
 
+        for step in 0..count
+        {
+            let block = stamped(chain.blocks.last().unwrap(), start + step * 10);
+            chain.add_block(block).unwrap();
+        }
+
+        chain
+    }
+
+    #[test]
+    fn moving_characters_between_fields_changes_the_hash()
+    {
+        let index = Uuid::new_v4();
+
+        let hash = calculate_hash(index, 1, &["ab", "", "", "cd"], 0, 0);
+
+        assert_ne!(hash, calculate_hash(index, 1, &["a", "", "", "bcd"], 0, 0));
+        assert_ne!(hash, calculate_hash(index, 1, &["", "ab", "", "cd"], 0, 0));
+        assert_ne!(calculate_hash(index, 1, &["x"], 12, 3), calculate_hash(index, 1, &["x"], 1, 23));
+    }
+
+    #[test]
+    fn a_transaction_is_only_confirmed_once()
+    {
+        let mut chain = chain();
+        let spent = transaction(1);
+
+        let twice = child(&chain.blocks[0], vec![spent.clone(), spent.clone()]);
+        assert!(matches!(chain.add_block(twice), Err(Error::DuplicateTransaction)));
+
+        let first = child(&chain.blocks[0], vec![spent.clone()]);
+        chain.add_block(first).unwrap();
+
+        let again = child(&chain.blocks[1], vec![transaction(2), spent]);
+        assert!(matches!(chain.add_block(again), Err(Error::DuplicateTransaction)));
+    }
+
+    #[test]
+    fn a_branch_may_confirm_what_it_replaces_but_only_once()
+    {
+        let mut chain = chain();
+        let moved = transaction(1);
+
+        let ours = child(&chain.blocks[0], vec![moved.clone()]);
+        chain.add_block(ours).unwrap();
+
+        let first = child(&chain.blocks[0], vec![moved.clone()]);
+        let repeat = child(&first, vec![moved.clone()]);
+        assert!(matches!(chain.reorganize(vec![first.clone(), repeat]), Err(Error::DuplicateTransaction)));
+
+        let second = child(&first, vec![transaction(2)]);
+        chain.reorganize(vec![first, second]).unwrap();
+        assert!(chain.is_confirmed(&moved.id));
+    }
+
+    #[test]
+    fn blocks_older_than_the_median_are_rejected()
+    {
+        let mut chain = history(3);
+        let median = chain.blocks[2].timestamp;
+
+        let early = stamped(chain.blocks.last().unwrap(), median - 1);
+        assert!(matches!(chain.add_block(early), Err(Error::TimestampTooOld)));
+
+        //Older than its parent is fine, as long as it isn't older than the median.
+        chain.add_block(stamped(chain.blocks.last().unwrap(), median)).unwrap();
+    }
+
+    #[test]
+    fn genesis_is_left_out_of_the_median()
+    {
+        let mut chain = chain();
+        let genesis = chain.blocks[0].timestamp;
+
+        chain.add_block(stamped(&chain.blocks[0].clone(), genesis - 3600)).unwrap();
+    }
+
+    #[test]
+    fn blocks_too_far_ahead_wait_for_network_time()
+    {
+        let mut chain = history(1);
+        let ahead = stamped(chain.blocks.last().unwrap(), Utc::now().timestamp() + MAX_FUTURE_DRIFT + 60);
+
+        assert!(matches!(chain.add_block(ahead.clone()), Err(Error::TimestampTooFarAhead)));
+
+        //Our peers' clocks say we're behind, so the block's time has come.
+        chain.set_time_offset(90);
+        chain.add_block(ahead).unwrap();
+    }
+
+    #[test]
+    fn reorganizations_check_timestamps_against_the_branch()
+    {
+        let mut chain = history(5);
+        let fork = chain.blocks[3].clone();
+
+        //Fine against the blocks below the fork alone, too old once the branch's first two blocks are in the median.
+        let first = stamped(&fork, fork.timestamp + 100);
+        let second = stamped(&first, fork.timestamp + 110);
+        let third = stamped(&second, fork.timestamp - 5);
+
+        assert!(matches!(chain.reorganize(vec![first.clone(), second, third]), Err(Error::TimestampTooOld)));
+
+        let second = stamped(&first, fork.timestamp + 110);
+        let third = stamped(&second, fork.timestamp + 120);
+        chain.reorganize(vec![first, second, third]).unwrap();
+    }
+}
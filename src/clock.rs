@@ -0,0 +1,127 @@
+use chrono::Utc;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+//A median offset past this means our peers are off, not us, so our own clock is kept as is.
+pub const MAX_TIME_ADJUSTMENT: i64 = 5 * 60;
+//Fewer peers than this could move our clock on their own, so it is left alone until enough have answered.
+pub const MIN_TIME_SAMPLES: usize = 5;
+
+//The lower middle for an even count, so half the values on one side can't pull it over by themselves.
+pub fn median(mut values: Vec<i64>) -> Option<i64>
+{
+    if values.is_empty()
+    {
+        return None;
+    }
+
+    values.sort_unstable();
+    values.get((values.len() - 1) / 2).copied()
+}
+
+//How far each peer's clock is from ours. Their median, counting our own clock, is the network time.
+#[derive(Debug, Default)]
+pub struct NetworkClock
+{
+    offsets: HashMap<PeerId, i64>,
+}
+
+impl NetworkClock
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn record(&mut self, peer: PeerId, peer_time: i64)
+    {
+        self.offsets.insert(peer, peer_time - Utc::now().timestamp());
+    }
+
+    pub fn forget(&mut self, peer: &PeerId)
+    {
+        self.offsets.remove(peer);
+    }
+
+    pub fn offset(&self) -> i64
+    {
+        if self.offsets.len() < MIN_TIME_SAMPLES
+        {
+            return 0;
+        }
+
+        let offset = median(self.offsets.values().copied().chain([0]).collect()).unwrap_or(0);
+
+        if offset.abs() > MAX_TIME_ADJUSTMENT { 0 } else { offset }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn clock(offsets: &[i64]) -> NetworkClock
+    {
+        NetworkClock { offsets: offsets.iter().map(|offset| (PeerId::random(), *offset)).collect() }
+    }
+
+    #[test]
+    fn median_of_unsorted_values()
+    {
+        assert_eq!(median(Vec::new()), None);
+        assert_eq!(median(vec![7]), Some(7));
+        assert_eq!(median(vec![9, 1, 5]), Some(5));
+        assert_eq!(median(vec![3, -4, 10, 8, 0]), Some(3));
+    }
+
+    #[test]
+    fn median_of_an_even_count_takes_the_lower_middle()
+    {
+        assert_eq!(median(vec![4, 1, 3, 2]), Some(2));
+        assert_eq!(median(vec![0, 100]), Some(0));
+    }
+
+    #[test]
+    fn our_clock_counts_as_a_peer()
+    {
+        assert_eq!(clock(&[10, 10, 10, 10, 10]).offset(), 10);
+        assert_eq!(clock(&[-30, 20, 40, 50, 60]).offset(), 20);
+        //With our own clock that's six offsets, and the lower middle of them is ours.
+        assert_eq!(clock(&[-1, -1, 0, 5, 5]).offset(), 0);
+    }
+
+    #[test]
+    fn too_few_peers_leave_our_clock_alone()
+    {
+        assert_eq!(clock(&[]).offset(), 0);
+        assert_eq!(clock(&[10]).offset(), 0);
+        assert_eq!(clock(&[10, 10, 10, 10]).offset(), 0);
+    }
+
+    #[test]
+    fn offsets_past_the_cap_are_ignored()
+    {
+        let far = MAX_TIME_ADJUSTMENT + 1;
+
+        assert_eq!(clock(&[far; MIN_TIME_SAMPLES]).offset(), 0);
+        assert_eq!(clock(&[MAX_TIME_ADJUSTMENT; MIN_TIME_SAMPLES]).offset(), MAX_TIME_ADJUSTMENT);
+    }
+
+    #[test]
+    fn forgotten_peers_no_longer_count()
+    {
+        let peers: Vec<PeerId> = (0..MIN_TIME_SAMPLES).map(|_| PeerId::random()).collect();
+        let mut clock = NetworkClock::new();
+
+        for peer in &peers
+        {
+            clock.record(*peer, Utc::now().timestamp() - 90);
+        }
+
+        assert!((-91..=-89).contains(&clock.offset()));
+
+        clock.forget(&peers[0]);
+        assert_eq!(clock.offset(), 0);
+    }
+}
@@ -0,0 +1,217 @@
+use serde::{ Serialize, Deserialize };
+use sha2::{ Sha256, Digest };
+use std::collections::HashMap;
+
+use crate::block::{ Block, BlockHeader };
+use crate::payload::Payload;
+use crate::transaction::{ Transaction, transactions_root };
+
+//Six bytes are plenty to tell apart the transactions of one mempool.
+#[cfg(not(test))]
+const SHORT_ID_LENGTH: usize = 12;
+//Three bytes in tests, short enough to find real collisions.
+#[cfg(test)]
+const SHORT_ID_LENGTH: usize = 6;
+
+//Salting with the block hash means a collision found for one block is useless for the next.
+pub fn short_id(block_hash: &str, transaction_id: &str) -> String
+{
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash);
+    hasher.update(transaction_id);
+
+    let mut id = hex::encode(hasher.finalize());
+    id.truncate(SHORT_ID_LENGTH);
+    id
+}
+
+//What gets gossiped for a new block, peers fill in the transactions from their own mempool.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound = "P: Payload")]
+pub struct CompactBlock<P = String>
+{
+    pub header: BlockHeader<P>,
+    pub short_ids: Vec<String>,
+}
+
+impl<P: Payload> CompactBlock<P>
+{
+    pub fn new(block: &Block<P>) -> Self
+    {
+        Self
+        {
+            header: block.header(),
+            short_ids: block.transactions.iter().map(|transaction| short_id(&block.hash, &transaction.id)).collect(),
+        }
+    }
+
+    //Matches the short ids against our pending transactions, ids that match nothing or more than one are left empty.
+    pub fn prefill(self, pending: &[Transaction]) -> PartialBlock<P>
+    {
+        let mut known: HashMap<String, Option<&Transaction>> = HashMap::new();
+
+        for transaction in pending
+        {
+            known.entry(short_id(&self.header.hash, &transaction.id))
+                .and_modify(|entry| *entry = None)
+                .or_insert(Some(transaction));
+        }
+
+        let transactions = self.short_ids.iter().map(|id| known.get(id).copied().flatten().cloned()).collect();
+
+        PartialBlock { compact: self, transactions }
+    }
+}
+
+//A compact block still waiting on some of its transactions.
+#[derive(Debug, Clone)]
+pub struct PartialBlock<P = String>
+{
+    pub compact: CompactBlock<P>,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl<P: Payload> PartialBlock<P>
+{
+    pub fn missing(&self) -> Vec<usize>
+    {
+        self.transactions.iter().enumerate().filter(|(_, transaction)| transaction.is_none()).map(|(index, _)| index).collect()
+    }
+
+    //Fills the missing slots in order, a transaction that doesn't match its short id leaves the slot empty.
+    pub fn fill(&mut self, transactions: Vec<Transaction>)
+    {
+        let hash = self.compact.header.hash.clone();
+
+        for (index, transaction) in self.missing().into_iter().zip(transactions)
+        {
+            if short_id(&hash, &transaction.id) == self.compact.short_ids[index]
+            {
+                self.transactions[index] = Some(transaction);
+            }
+        }
+    }
+
+    //None while any transaction is missing, or when a short id collision filled a slot with the wrong one and the
+    //transactions don't add up to the merkle root. The block has to be fetched in full then.
+    pub fn into_block(self) -> Option<Block<P>>
+    {
+        let transactions = self.transactions.into_iter().collect::<Option<Vec<_>>>()?;
+
+        if transactions_root(&transactions) != self.compact.header.merkle_root
+        {
+            return None;
+        }
+
+        let mut block = self.compact.header.into_block();
+        block.transactions = transactions;
+
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::{ BlockCandidate, BlockState };
+
+    fn block_with(transactions: Vec<Transaction>) -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        let candidate = BlockCandidate::new(&chain.blocks[0], String::from("compact"), transactions);
+        let hash = candidate.hash(0);
+
+        candidate.into_block(hash, 0)
+    }
+
+    fn transaction(amount: u64) -> Transaction
+    {
+        Transaction::new(String::from("alice"), String::from("bob"), amount)
+    }
+
+    #[test]
+    fn rebuilds_from_the_mempool()
+    {
+        let transactions = vec![transaction(1), transaction(2), transaction(3)];
+        let block = block_with(transactions.clone());
+
+        let partial = CompactBlock::new(&block).prefill(&transactions);
+        assert!(partial.missing().is_empty());
+
+        let rebuilt = partial.into_block().unwrap();
+        assert_eq!(rebuilt.hash, block.hash);
+        assert_eq!(rebuilt.merkle_root, block.merkle_root);
+        assert_eq!(rebuilt.transactions.len(), 3);
+    }
+
+    //Two different transactions whose short ids collide under block_hash.
+    fn colliding_pair(block_hash: &str) -> (Transaction, Transaction)
+    {
+        let mut seen = HashMap::new();
+
+        for amount in 100..
+        {
+            let transaction = transaction(amount);
+
+            if let Some(other) = seen.insert(short_id(block_hash, &transaction.id), transaction.clone())
+            {
+                return (other, transaction);
+            }
+        }
+
+        unreachable!()
+    }
+
+    #[test]
+    fn falls_back_after_a_short_id_collision()
+    {
+        //The salt has to be known before the block it salts is built, so the compact block is rehashed onto a fixed one.
+        let salt = String::from("collision");
+        let (wanted, impostor) = colliding_pair(&salt);
+
+        let transactions = vec![transaction(1), wanted.clone(), transaction(3)];
+        let block = block_with(transactions.clone());
+
+        let mut compact = CompactBlock::new(&block);
+        compact.header.hash = salt.clone();
+        compact.short_ids = transactions.iter().map(|transaction| short_id(&salt, &transaction.id)).collect();
+
+        let pending = vec![transactions[0].clone(), impostor.clone(), wanted.clone(), transactions[2].clone()];
+        let partial = compact.prefill(&pending);
+
+        //Both candidates match the short id, so neither is picked.
+        assert_eq!(partial.missing(), vec![1]);
+
+        //A peer answering with the wrong one of the pair fills the slot, but the merkle root gives it away.
+        let mut wrong = partial.clone();
+        wrong.fill(vec![impostor]);
+        assert!(wrong.missing().is_empty());
+        assert!(wrong.into_block().is_none());
+
+        let mut right = partial;
+        right.fill(vec![wanted.clone()]);
+
+        let rebuilt = right.into_block().unwrap();
+        assert_eq!(rebuilt.transactions[1].id, wanted.id);
+        assert_eq!(rebuilt.merkle_root, block.merkle_root);
+    }
+
+    #[test]
+    fn fill_skips_transactions_that_do_not_match()
+    {
+        let transactions = vec![transaction(1), transaction(2)];
+        let block = block_with(transactions.clone());
+
+        let mut partial = CompactBlock::new(&block).prefill(&[]);
+        assert_eq!(partial.missing(), vec![0, 1]);
+
+        partial.fill(vec![transaction(5), transactions[1].clone()]);
+        assert_eq!(partial.missing(), vec![0]);
+
+        partial.fill(vec![transactions[0].clone()]);
+        assert!(partial.into_block().is_some());
+    }
+}
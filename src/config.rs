@@ -0,0 +1,256 @@
+use libp2p::{ Multiaddr, multiaddr::Protocol };
+use std::net::{ Ipv4Addr, SocketAddr };
+
+use crate::authority::DEFAULT_SLOT_TIME;
+use crate::error::Error;
+use crate::stake::{ DEFAULT_EPOCH_LENGTH, DEFAULT_GENESIS_STAKE };
+use crate::wire::Encoding;
+
+const DEFAULT_TCP_ADDR: &str = "/ip4/0.0.0.0/tcp/0";
+const DEFAULT_QUIC_ADDR: &str = "/ip4/0.0.0.0/udp/0/quic-v1";
+const DEFAULT_MINING_THREADS: usize = 1;
+const DEFAULT_STATS_INTERVAL: u64 = 60;
+const DEFAULT_SHARE_DIFFICULTY: usize = 2;
+const DEFAULT_PPLNS_WINDOW: usize = 1000;
+
+const DEFAULT_FILE_PATH: &str = "blockchain.json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Consensus
+{
+    ProofOfWork,
+    ProofOfAuthority,
+    ProofOfStake,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config
+{
+    pub file_path: String,
+    pub tcp_listen: Vec<Multiaddr>,
+    pub quic_listen: Vec<Multiaddr>,
+    pub dial: Vec<Multiaddr>,
+    pub mining_threads: usize,
+    pub mining: bool,
+    pub stats_interval: u64,
+    pub mining_server: Option<SocketAddr>,
+    pub pool: bool,
+    pub share_difficulty: usize,
+    pub pplns_window: usize,
+    pub rpc: Option<SocketAddr>,
+    pub rest: Option<SocketAddr>,
+    pub ws: Option<SocketAddr>,
+    pub consensus: Consensus,
+    pub validators: Vec<String>,
+    pub validator_key: Option<String>,
+    pub slot_time: u64,
+    pub genesis_stake: u64,
+    pub epoch_length: u64,
+    pub finality: bool,
+    pub generate_key: bool,
+    pub light: bool,
+    pub wire: Encoding,
+    pub checkpoints: Vec<(u64, String)>,
+    pub assume_valid: Option<(u64, String)>,
+}
+
+impl Default for Config
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            file_path: String::from(DEFAULT_FILE_PATH),
+            tcp_listen: vec![DEFAULT_TCP_ADDR.parse().unwrap()],
+            quic_listen: vec![DEFAULT_QUIC_ADDR.parse().unwrap()],
+            dial: Vec::new(),
+            mining_threads: DEFAULT_MINING_THREADS,
+            mining: true,
+            stats_interval: DEFAULT_STATS_INTERVAL,
+            mining_server: None,
+            pool: false,
+            share_difficulty: DEFAULT_SHARE_DIFFICULTY,
+            pplns_window: DEFAULT_PPLNS_WINDOW,
+            rpc: None,
+            rest: None,
+            ws: None,
+            consensus: Consensus::ProofOfWork,
+            validators: Vec::new(),
+            validator_key: None,
+            slot_time: DEFAULT_SLOT_TIME,
+            genesis_stake: DEFAULT_GENESIS_STAKE,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            finality: false,
+            generate_key: false,
+            light: false,
+            wire: Encoding::Cbor,
+            checkpoints: Vec::new(),
+            assume_valid: None,
+        }
+    }
+}
+
+impl Config
+{
+    //Flags: --file <path>, --tcp <multiaddr>, --quic <multiaddr>, --threads <n>, --no-mine, --stats-interval <secs>,
+    //--mining-server <[ip:]port>, --pool, --share-difficulty <n>, --pplns-window <n>, --rpc <ip:port>,
+    //--rest <ip:port>, --ws <ip:port>, --consensus <pow|poa|pos>, --validators <key,key,...>, --validator-key <secret>,
+    //--slot-time <secs>, --genesis-stake <n>, --epoch-length <blocks>, --finality, --generate-key, --light, --wire <cbor|json>,
+    //--checkpoint <height:hash>, --assume-valid <height:hash>. Anything else is treated as a peer address to dial.
+    pub fn from_args() -> Result<Self, Error>
+    {
+        let mut config = Self::default();
+        let mut tcp_listen = Vec::new();
+        let mut quic_listen = Vec::new();
+
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next()
+        {
+            match arg.as_str()
+            {
+                "--file" => config.file_path = next_value(&mut args, &arg)?,
+                "--tcp" => tcp_listen.push(next_value(&mut args, &arg)?.parse()?),
+                "--quic" => quic_listen.push(next_value(&mut args, &arg)?.parse()?),
+                "--threads" => config.mining_threads = parse_number(&next_value(&mut args, &arg)?)?,
+                "--no-mine" => config.mining = false,
+                "--stats-interval" => config.stats_interval = parse_number(&next_value(&mut args, &arg)?)?.max(1) as u64,
+                "--mining-server" => config.mining_server = Some(parse_local_socket(&next_value(&mut args, &arg)?)?),
+                "--pool" => config.pool = true,
+                "--share-difficulty" => config.share_difficulty = parse_number(&next_value(&mut args, &arg)?)?,
+                "--pplns-window" => config.pplns_window = parse_number(&next_value(&mut args, &arg)?)?,
+                "--rpc" => config.rpc = Some(parse_socket(&next_value(&mut args, &arg)?)?),
+                "--rest" => config.rest = Some(parse_socket(&next_value(&mut args, &arg)?)?),
+                "--ws" => config.ws = Some(parse_socket(&next_value(&mut args, &arg)?)?),
+                "--consensus" => config.consensus = parse_consensus(&next_value(&mut args, &arg)?)?,
+                "--validators" => config.validators = next_value(&mut args, &arg)?.split(',').map(str::to_owned).collect(),
+                "--validator-key" => config.validator_key = Some(next_value(&mut args, &arg)?),
+                "--slot-time" => config.slot_time = parse_number(&next_value(&mut args, &arg)?)?.max(1) as u64,
+                "--genesis-stake" => config.genesis_stake = parse_number(&next_value(&mut args, &arg)?)?.max(1) as u64,
+                "--epoch-length" => config.epoch_length = parse_number(&next_value(&mut args, &arg)?)?.max(1) as u64,
+                "--finality" => config.finality = true,
+                "--generate-key" => config.generate_key = true,
+                "--light" => config.light = true,
+                "--wire" => config.wire = parse_encoding(&next_value(&mut args, &arg)?)?,
+                "--checkpoint" => config.checkpoints.push(parse_checkpoint(&next_value(&mut args, &arg)?)?),
+                "--assume-valid" => config.assume_valid = Some(parse_checkpoint(&next_value(&mut args, &arg)?)?),
+                _ if arg.starts_with("--") => return Err(Error::InvalidArgument(arg)),
+                _ => config.dial.push(arg.parse()?),
+            }
+        }
+
+        if config.rpc.is_some_and(|addr| !addr.ip().is_loopback())
+        {
+            return Err(Error::InvalidArgument(String::from("--rpc has no authentication and can shut the node down, bind it to a loopback address")));
+        }
+
+        if config.pool && config.mining_server.is_none()
+        {
+            return Err(Error::InvalidArgument(String::from("--pool needs --mining-server")));
+        }
+
+        //External miners only search nonces, they can't sign a validator's block.
+        if config.mining_server.is_some() && config.consensus != Consensus::ProofOfWork
+        {
+            return Err(Error::InvalidArgument(String::from("--mining-server and --pool need --consensus pow")));
+        }
+
+        if config.consensus != Consensus::ProofOfWork && config.validators.is_empty()
+        {
+            return Err(Error::InvalidArgument(String::from("--consensus poa and pos need --validators")));
+        }
+
+        if config.finality && config.validators.is_empty()
+        {
+            return Err(Error::InvalidArgument(String::from("--finality needs --validators")));
+        }
+
+        if config.light && (config.mining_server.is_some() || config.finality)
+        {
+            return Err(Error::InvalidArgument(String::from("--light can't serve templates or vote on finality")));
+        }
+
+        //A light client has no transactions to put in blocks.
+        if config.light
+        {
+            config.mining = false;
+        }
+
+        if !tcp_listen.is_empty()
+        {
+            config.tcp_listen = tcp_listen;
+        }
+
+        if !quic_listen.is_empty()
+        {
+            config.quic_listen = quic_listen;
+        }
+
+        Ok(config)
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, Error>
+{
+    args.next().ok_or_else(|| Error::InvalidArgument(format!("{flag} expects a value")))
+}
+
+fn parse_number(value: &str) -> Result<usize, Error>
+{
+    value.parse().map_err(|_| Error::InvalidArgument(format!("{value} is not a valid number")))
+}
+
+fn parse_consensus(value: &str) -> Result<Consensus, Error>
+{
+    match value
+    {
+        "pow" => Ok(Consensus::ProofOfWork),
+        "poa" => Ok(Consensus::ProofOfAuthority),
+        "pos" => Ok(Consensus::ProofOfStake),
+        _ => Err(Error::InvalidArgument(format!("{value} is not a consensus engine, expected pow, poa or pos"))),
+    }
+}
+
+fn parse_encoding(value: &str) -> Result<Encoding, Error>
+{
+    match value
+    {
+        "json" => Ok(Encoding::Json),
+        "cbor" => Ok(Encoding::Cbor),
+        _ => Err(Error::InvalidArgument(format!("{value} is not a wire encoding, expected cbor or json"))),
+    }
+}
+
+fn parse_checkpoint(value: &str) -> Result<(u64, String), Error>
+{
+    let (height, hash) = value.split_once(':').ok_or_else(|| Error::InvalidArgument(format!("{value} is not a checkpoint, expected height:hash")))?;
+
+    Ok((parse_number(height)? as u64, hash.to_owned()))
+}
+
+fn parse_socket(value: &str) -> Result<SocketAddr, Error>
+{
+    value.parse().map_err(|_| Error::InvalidArgument(format!("{value} is not a valid socket address")))
+}
+
+//A bare port binds to loopback, serving other hosts takes spelling out the address.
+fn parse_local_socket(value: &str) -> Result<SocketAddr, Error>
+{
+    match value.parse::<u16>()
+    {
+        Ok(port) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+        Err(_) => parse_socket(value),
+    }
+}
+
+pub fn is_quic(addr: &Multiaddr) -> bool
+{
+    addr.iter().any(|protocol| matches!(protocol, Protocol::QuicV1))
+}
+
+//QUIC addresses go first, and since peers are dialed one address at a time TCP is only tried once QUIC fails.
+pub fn prefer_quic(mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr>
+{
+    addrs.sort_by_key(|addr| !is_quic(addr));
+    addrs
+}
@@ -0,0 +1,268 @@
+use serde::{ Serialize, Deserialize };
+use sha2::{ Sha256, Digest };
+use std::fmt;
+use std::time::Duration;
+
+use crate::block::{ Block, BlockCandidate, DEFAULT_PREFIX };
+use crate::error::Error;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+//Engine specific entries carried inside a block and covered by its hash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ConsensusRecord
+{
+    //The block signer's vote to add or remove a validator.
+    Vote { validator: String, add: bool },
+    //Locks up stake paid from the staker's balance, only valid in a block the staker proposed itself.
+    Deposit { staker: String, amount: u64 },
+    //Releases stake, only valid in a block the staker proposed itself.
+    Withdraw { staker: String, amount: u64 },
+    //The proposer's randomness contribution for the epoch, its signature over "randao:{epoch}".
+    Reveal { signature: String },
+    Slash(DoubleSign),
+}
+
+//Two signatures from the same key over different blocks in the same slot.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct DoubleSign
+{
+    pub signer: String,
+    pub slot: u64,
+    pub first_hash: String,
+    pub first_signature: String,
+    pub second_hash: String,
+    pub second_signature: String,
+}
+
+//Hex encoded ed25519 public key and signature over the block hash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockSignature
+{
+    pub signer: String,
+    pub signature: String,
+}
+
+pub fn records_commitment(records: &[ConsensusRecord]) -> String
+{
+    if records.is_empty()
+    {
+        return String::new();
+    }
+
+    let mut hasher = Sha256::new();
+
+    for record in records
+    {
+        hasher.update(serde_json::to_vec(record).unwrap_or_default());
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+//Everything that decides who may extend the chain and which branch wins. BlockState and the p2p layer only go through this.
+pub trait ConsensusEngine<P: Payload = String>: fmt::Debug + Send + Sync
+{
+    //Builds the next candidate on top of parent.
+    fn prepare(&self, parent: &Block<P>, data: P, transactions: Vec<Transaction>) -> BlockCandidate<P>
+    {
+        BlockCandidate::new(parent, data, transactions)
+    }
+
+    //How long to hold off before sealing the candidate, None when this node may not seal it at all.
+    fn ready_in(&self, _candidate: &BlockCandidate<P>) -> Option<Duration>
+    {
+        Some(Duration::ZERO)
+    }
+
+    //A single sealing attempt, None means the candidate could not be sealed with this nonce.
+    fn seal(&self, candidate: &BlockCandidate<P>, nonce: u64) -> Option<Block<P>>;
+
+    //Checks the seal of a block that already links to parent. It changes nothing, accept does that once the block joins the chain.
+    fn verify_seal(&self, block: &Block<P>, parent: &Block<P>) -> Result<(), Error>;
+
+    //The part of verify_seal that needs no parent, for blocks that arrive before theirs. Like verify_seal it changes nothing.
+    fn verify_header(&self, block: &Block<P>) -> Result<(), Error>
+    {
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        Ok(())
+    }
+
+    //Records whatever the engine tracks per block, for a block that passed verify_seal and is joining the chain.
+    fn accept(&self, _block: &Block<P>, _parent: &Block<P>) {}
+
+    //Forgets what it tracks for blocks below height, no reorganization can reach back that far any more.
+    fn prune(&self, _height: u64) {}
+
+    //Fork choice picks the branch with the most accumulated weight.
+    fn weight(&self, block: &Block<P>) -> u128;
+
+    //Sees every block that arrives, whether or not it ends up on the chain.
+    fn observe(&self, _block: &Block<P>) {}
+
+    //Rebuilds whatever the engine tracks per block for a chain that was loaded from disk rather than verified.
+    fn replay(&self, chain: &[Block<P>])
+    {
+        for pair in chain.windows(2)
+        {
+            self.accept(&pair[1], &pair[0]);
+        }
+    }
+
+    //Queues a record for the blocks this node seals.
+    fn submit_record(&self, _record: ConsensusRecord) -> Result<(), Error>
+    {
+        Err(Error::UnsupportedRecord)
+    }
+
+    //The validators allowed to seal on top of tip, empty for permissionless engines.
+    fn validators(&self, _tip: &Block<P>) -> Vec<String>
+    {
+        Vec::new()
+    }
+}
+
+//The original SHA-256 engine, a block is sealed once its hash starts with the prefix.
+#[derive(Debug, Clone)]
+pub struct ProofOfWork
+{
+    pub prefix: String,
+}
+
+impl Default for ProofOfWork
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            prefix: DEFAULT_PREFIX.to_owned(),
+        }
+    }
+}
+
+impl ProofOfWork
+{
+    pub fn check_prefix<P: Payload>(&self, block: &Block<P>) -> Result<(), Error>
+    {
+        if !block.hash.starts_with(&self.prefix)
+        {
+            return Err(Error::InvalidPrefix);
+        }
+
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Payload> ConsensusEngine<P> for ProofOfWork
+{
+    fn seal(&self, candidate: &BlockCandidate<P>, nonce: u64) -> Option<Block<P>>
+    {
+        let hash = candidate.hash(nonce);
+
+        if hash.starts_with(&self.prefix)
+        {
+            return Some(candidate.clone().into_block(hash, nonce));
+        }
+
+        None
+    }
+
+    fn verify_seal(&self, block: &Block<P>, _parent: &Block<P>) -> Result<(), Error>
+    {
+        self.check_prefix(block)
+    }
+
+    fn verify_header(&self, block: &Block<P>) -> Result<(), Error>
+    {
+        self.check_prefix(block)
+    }
+
+    //Every prefix character is a hex digit, so each one makes a block sixteen times harder to find.
+    fn weight(&self, _block: &Block<P>) -> u128
+    {
+        16u128.pow(self.prefix.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::BlockState;
+
+    fn genesis() -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        chain.blocks.remove(0)
+    }
+
+    //Walks the nonces until the engine seals, a one character prefix takes 16 tries on average.
+    fn mine(engine: &ProofOfWork, parent: &Block) -> Block
+    {
+        let candidate = ConsensusEngine::<String>::prepare(engine, parent, String::from("pow"), Vec::new());
+
+        (0..).find_map(|nonce| engine.seal(&candidate, nonce)).unwrap()
+    }
+
+    #[test]
+    fn sealed_blocks_meet_the_prefix()
+    {
+        let engine = ProofOfWork { prefix: String::from("a") };
+        let genesis = genesis();
+        let block = mine(&engine, &genesis);
+
+        assert!(block.hash.starts_with('a'));
+        assert!(engine.verify_seal(&block, &genesis).is_ok());
+        assert!(engine.verify_header(&block).is_ok());
+    }
+
+    #[test]
+    fn a_harder_prefix_rejects_the_seal()
+    {
+        let genesis = genesis();
+        let block = mine(&ProofOfWork { prefix: String::from("a") }, &genesis);
+
+        //One more character, picked so the block's hash doesn't have it.
+        let next = if block.hash.as_bytes()[1] == b'0' { '1' } else { '0' };
+        let harder = ProofOfWork { prefix: format!("a{next}") };
+
+        assert!(matches!(harder.verify_seal(&block, &genesis), Err(Error::InvalidPrefix)));
+        assert!(matches!(harder.verify_header(&block), Err(Error::InvalidPrefix)));
+    }
+
+    #[test]
+    fn tampering_breaks_the_hash()
+    {
+        let engine = ProofOfWork { prefix: String::from("a") };
+        let genesis = genesis();
+
+        let mut block = mine(&engine, &genesis);
+        block.nonce += 1;
+        assert!(matches!(engine.verify_header(&block), Err(Error::InvalidHash)));
+
+        let mut block = mine(&engine, &genesis);
+        block.data = String::from("rewritten");
+        assert!(matches!(engine.verify_seal(&block, &genesis), Err(Error::InvalidHash)));
+    }
+
+    #[test]
+    fn each_prefix_character_multiplies_the_weight_by_sixteen()
+    {
+        let block = genesis();
+
+        assert_eq!(ConsensusEngine::<String>::weight(&ProofOfWork { prefix: String::new() }, &block), 1);
+        assert_eq!(ConsensusEngine::<String>::weight(&ProofOfWork::default(), &block), 16u128.pow(4));
+    }
+}
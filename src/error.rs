@@ -8,6 +8,24 @@ pub enum Error
     ChainTooShort,
     FileNotFound,
     IOFailure,
+    InvalidTransaction,
+    InvalidArgument(String),
+    NodeStopped,
+    UnauthorizedSigner,
+    InvalidSignature,
+    InvalidSlot,
+    UnsupportedRecord,
+    FinalizedBlock,
+    InvalidMerkleRoot,
+    BlockTooLarge,
+    InvalidProof,
+    TransactionNotFound,
+    RequestFailed(String),
+    CheckpointMismatch,
+    TimestampTooOld,
+    TimestampTooFarAhead,
+    MempoolFull,
+    DuplicateTransaction,
 
     NetworkInfallible(String),
     NetworkMultiaddr(String),
@@ -25,6 +43,8 @@ impl std::fmt::Display for Error
             Self::NetworkMultiaddr(err) => write!(fmt, "Network Multiaddr Error: {}", err),
             Self::NetworkTransport(err) => write!(fmt, "Network Transport Error: {}", err),
             Self::NetworkDial(err) => write!(fmt, "Network Dial Error: {}", err),
+            Self::InvalidArgument(arg) => write!(fmt, "Invalid Argument: {}", arg),
+            Self::RequestFailed(err) => write!(fmt, "Request Failed: {}", err),
             _ => write!(fmt, "{:?}", self),
         }
     }
@@ -0,0 +1,34 @@
+use serde::Serialize;
+
+use crate::block::Block;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+//Everything the main event loop announces to subscribers, serialized as {"event": ..., "data": ...}.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", content = "data", rename_all = "camelCase", bound = "P: Payload")]
+pub enum NodeEvent<P = String>
+{
+    NewBlock(Block<P>),
+    Reorg { removed: Vec<Block<P>>, added: Vec<Block<P>> },
+    Finalized { height: u64, hash: String },
+    NewTransaction(Transaction),
+    PeerConnected(String),
+    PeerDisconnected(String),
+}
+
+impl<P> NodeEvent<P>
+{
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Self::NewBlock(_) => "newBlock",
+            Self::Reorg { .. } => "reorg",
+            Self::Finalized { .. } => "finalized",
+            Self::NewTransaction(_) => "newTransaction",
+            Self::PeerConnected(_) => "peerConnected",
+            Self::PeerDisconnected(_) => "peerDisconnected",
+        }
+    }
+}
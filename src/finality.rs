@@ -0,0 +1,397 @@
+use libp2p::identity::ed25519::Keypair;
+use serde::{ Serialize, Deserialize };
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::sync::Mutex;
+
+use crate::authority::verify_signature;
+use crate::block::BlockState;
+use crate::error::Error;
+use crate::payload::Payload;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum VoteKind
+{
+    Prevote,
+    Precommit,
+}
+
+//A validator's vote for the block at height in one round, signed over "finality:{kind}:{height}:{round}:{hash}".
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FinalityVote
+{
+    pub kind: VoteKind,
+    pub height: u64,
+    pub round: u32,
+    pub hash: String,
+    pub voter: String,
+    pub signature: String,
+}
+
+//Where this validator stands at one height.
+#[derive(Debug, Default)]
+struct HeightState
+{
+    round: u32,
+    prevoted: HashMap<u32, String>,
+    precommitted: HashSet<u32>,
+    //Taken on a prevote quorum. From then on we prevote only this block, until a quorum in a later round moves it.
+    lock: Option<(u32, String)>,
+}
+
+#[derive(Debug, Default)]
+struct Rounds
+{
+    heights: HashMap<u64, HeightState>,
+    votes: HashMap<(VoteKind, u64, u32, String), HashSet<String>>,
+    certified: BTreeMap<u64, String>,
+}
+
+//Two phase voting per height and round. A validator prevotes the block it holds at a height. Once a block has
+//prevotes from more than two thirds of the committee, validators lock on it and precommit it, and more than two thirds
+//of precommits make it final together with all of its ancestors. A validator that sees a different block at a height
+//it already prevoted on moves to the next round, and more than a third of the committee in a later round pulls the
+//rest along.
+#[derive(Debug)]
+pub struct FinalityGadget
+{
+    validators: Vec<String>,
+    key: Option<Keypair>,
+    rounds: Mutex<Rounds>,
+}
+
+impl FinalityGadget
+{
+    pub fn new(validators: Vec<String>, key: Option<Keypair>) -> Self
+    {
+        Self
+        {
+            validators,
+            key,
+            rounds: Mutex::new(Rounds::default()),
+        }
+    }
+
+    //The engine's validator set on top of the block below height, or the configured one for engines without a set.
+    fn committee<P: Payload>(&self, chain: &BlockState<P>, height: u64) -> Option<Vec<String>>
+    {
+        let parent = chain.blocks.get(height.checked_sub(1)? as usize)?;
+        let validators = chain.engine().validators(parent);
+
+        Some(if validators.is_empty() { self.validators.clone() } else { validators })
+    }
+
+    //Prevotes the block we just took as our tip, or the block we are locked on at its height. Returns the votes to gossip.
+    pub fn vote<P: Payload>(&self, chain: &BlockState<P>, height: u64, hash: &str) -> Vec<FinalityVote>
+    {
+        let Some(committee) = self.committee(chain, height) else { return Vec::new() };
+        let mut rounds = self.rounds.lock().unwrap();
+
+        let state = rounds.heights.entry(height).or_default();
+        let target = state.lock.as_ref().map_or(hash, |(_, locked)| locked.as_str()).to_owned();
+
+        match state.prevoted.get(&state.round)
+        {
+            Some(prevoted) if *prevoted == target => return Vec::new(),
+            Some(_) => state.round += 1,
+            None => {},
+        }
+
+        let round = state.round;
+        self.cast(&mut rounds, &committee, VoteKind::Prevote, height, round, &target)
+    }
+
+    //A vote from the network has to be signed by a member of the committee for its height. FinalizedBlock and
+    //OutOfBounds mean the height is behind us or beyond our chain, so the vote can't be judged.
+    pub fn check_vote<P: Payload>(&self, chain: &BlockState<P>, vote: &FinalityVote) -> Result<Vec<String>, Error>
+    {
+        if vote.height <= chain.finalized
+        {
+            return Err(Error::FinalizedBlock);
+        }
+
+        let committee = self.committee(chain, vote.height).ok_or(Error::OutOfBounds)?;
+
+        if !committee.contains(&vote.voter)
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !verify_signature(&vote.voter, vote_message(vote.kind, vote.height, vote.round, &vote.hash).as_bytes(), &vote.signature)
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(committee)
+    }
+
+    //Counts a vote from the network. Returns our own votes it triggered, to be gossiped.
+    pub fn add_vote<P: Payload>(&self, chain: &BlockState<P>, vote: &FinalityVote) -> Vec<FinalityVote>
+    {
+        let Ok(committee) = self.check_vote(chain, vote) else { return Vec::new() };
+
+        let mut rounds = self.rounds.lock().unwrap();
+        let mut ours = self.count(&mut rounds, &committee, vote);
+
+        //More than a third of the committee is already in a later round, so at least one honest validator is too.
+        let state = rounds.heights.entry(vote.height).or_default();
+
+        if vote.round > state.round && voters_in_round(&rounds, vote.height, vote.round) * 3 > committee.len()
+        {
+            let state = rounds.heights.entry(vote.height).or_default();
+            state.round = vote.round;
+
+            let target = state.lock.as_ref().map(|(_, locked)| locked.clone())
+                .or_else(|| chain.blocks.get(vote.height as usize).map(|block| block.hash.clone()));
+
+            if let Some(target) = target.filter(|_| !state.prevoted.contains_key(&vote.round))
+            {
+                ours.extend(self.cast(&mut rounds, &committee, VoteKind::Prevote, vote.height, vote.round, &target));
+            }
+        }
+
+        ours
+    }
+
+    //Signs and counts our own vote, along with whatever it sets off. Nothing for a node outside the committee.
+    fn cast(&self, rounds: &mut Rounds, committee: &[String], kind: VoteKind, height: u64, round: u32, hash: &str) -> Vec<FinalityVote>
+    {
+        let Some(key) = &self.key else { return Vec::new() };
+        let voter = hex::encode(key.public().to_bytes());
+
+        if !committee.contains(&voter)
+        {
+            return Vec::new();
+        }
+
+        let state = rounds.heights.entry(height).or_default();
+
+        match kind
+        {
+            VoteKind::Prevote => _ = state.prevoted.insert(round, hash.to_owned()),
+            VoteKind::Precommit => _ = state.precommitted.insert(round),
+        }
+
+        let vote = FinalityVote
+        {
+            kind,
+            height,
+            round,
+            hash: hash.to_owned(),
+            signature: hex::encode(key.sign(vote_message(kind, height, round, hash).as_bytes())),
+            voter,
+        };
+
+        let mut votes = self.count(rounds, committee, &vote);
+        votes.insert(0, vote);
+        votes
+    }
+
+    fn count(&self, rounds: &mut Rounds, committee: &[String], vote: &FinalityVote) -> Vec<FinalityVote>
+    {
+        let voters = rounds.votes.entry((vote.kind, vote.height, vote.round, vote.hash.clone())).or_default();
+
+        if !voters.insert(vote.voter.clone()) || voters.len() * 3 <= committee.len() * 2
+        {
+            return Vec::new();
+        }
+
+        match vote.kind
+        {
+            VoteKind::Precommit =>
+            {
+                if rounds.certified.insert(vote.height, vote.hash.clone()).is_none()
+                {
+                    println!("Block {} at height {} has a finality quorum in round {}", vote.hash, vote.height, vote.round);
+                }
+
+                Vec::new()
+            }
+            VoteKind::Prevote =>
+            {
+                let state = rounds.heights.entry(vote.height).or_default();
+
+                //A quorum in a later round than our lock is the only thing that can move it.
+                if state.lock.as_ref().is_some_and(|(locked_round, _)| *locked_round > vote.round)
+                {
+                    return Vec::new();
+                }
+
+                state.lock = Some((vote.round, vote.hash.clone()));
+                state.round = state.round.max(vote.round);
+
+                if state.round != vote.round || state.precommitted.contains(&vote.round)
+                {
+                    return Vec::new();
+                }
+
+                self.cast(rounds, committee, VoteKind::Precommit, vote.height, vote.round, &vote.hash)
+            }
+        }
+    }
+
+    //Finalizes the highest certified block our chain agrees with. Certificates for blocks we don't have yet are kept
+    //until sync catches up. Returns the new finalized height.
+    pub fn apply<P: Payload>(&self, chain: &mut BlockState<P>) -> Option<u64>
+    {
+        let mut rounds = self.rounds.lock().unwrap();
+
+        let (height, _) = rounds.certified.iter().rev()
+            .filter(|(height, _)| **height > chain.finalized)
+            .find(|(height, hash)| chain.blocks.get(**height as usize).is_some_and(|block| &block.hash == *hash))?;
+
+        let height = *height;
+        chain.finalize(height).ok()?;
+
+        rounds.certified.retain(|certified_height, _| *certified_height > height);
+        rounds.votes.retain(|(_, vote_height, _, _), _| *vote_height > height);
+        rounds.heights.retain(|state_height, _| *state_height > height);
+
+        Some(height)
+    }
+}
+
+fn voters_in_round(rounds: &Rounds, height: u64, round: u32) -> usize
+{
+    rounds.votes.iter()
+        .filter(|((_, vote_height, vote_round, _), _)| *vote_height == height && *vote_round == round)
+        .flat_map(|(_, voters)| voters.iter())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+fn vote_message(kind: VoteKind, height: u64, round: u32, hash: &str) -> String
+{
+    let kind = match kind
+    {
+        VoteKind::Prevote => "prevote",
+        VoteKind::Precommit => "precommit",
+    };
+
+    format!("finality:{kind}:{height}:{round}:{hash}")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::{ Block, BlockCandidate };
+    use crate::consensus::ProofOfWork;
+    use std::sync::Arc;
+
+    //A committee of four, each member with its own gadget.
+    fn committee() -> Vec<FinalityGadget>
+    {
+        let keys: Vec<Keypair> = (0..4).map(|_| Keypair::generate()).collect();
+        let validators: Vec<String> = keys.iter().map(|key| hex::encode(key.public().to_bytes())).collect();
+
+        keys.into_iter().map(|key| FinalityGadget::new(validators.clone(), Some(key))).collect()
+    }
+
+    //Genesis and two blocks on top of it, sealed by an engine that takes any hash.
+    fn chain() -> BlockState
+    {
+        let mut chain = BlockState::new();
+        chain.set_engine(Arc::new(ProofOfWork { prefix: String::new() }));
+        chain.create_genesis_block();
+
+        for _ in 0..2
+        {
+            chain.add_block(child(chain.blocks.last().unwrap())).unwrap();
+        }
+
+        chain
+    }
+
+    fn child(parent: &Block) -> Block
+    {
+        let candidate = BlockCandidate::new(parent, String::from("final"), Vec::new());
+        let hash = candidate.hash(0);
+
+        candidate.into_block(hash, 0)
+    }
+
+    //Prevotes from the given members for the block at height, passed around until nobody has anything left to say.
+    fn run(gadgets: &[FinalityGadget], voting: usize, chain: &BlockState, height: u64)
+    {
+        let hash = chain.blocks[height as usize].hash.clone();
+        let mut gossip: Vec<FinalityVote> = gadgets[..voting].iter().flat_map(|gadget| gadget.vote(chain, height, &hash)).collect();
+
+        while let Some(vote) = gossip.pop()
+        {
+            for gadget in &gadgets[..voting]
+            {
+                if hex::encode(gadget.key.as_ref().unwrap().public().to_bytes()) != vote.voter
+                {
+                    gossip.extend(gadget.add_vote(chain, &vote));
+                }
+            }
+        }
+    }
+
+    fn certified(gadget: &FinalityGadget, height: u64) -> Option<String>
+    {
+        gadget.rounds.lock().unwrap().certified.get(&height).cloned()
+    }
+
+    #[test]
+    fn three_of_four_finalize_a_block()
+    {
+        let gadgets = committee();
+        let mut chain = chain();
+
+        run(&gadgets, 3, &chain, 2);
+
+        for gadget in &gadgets[..3]
+        {
+            assert_eq!(certified(gadget, 2), Some(chain.blocks[2].hash.clone()));
+        }
+
+        assert_eq!(gadgets[0].apply(&mut chain), Some(2));
+        assert_eq!(chain.finalized, 2);
+
+        //Nothing can replace a finalized block, however heavy the branch.
+        let branch = vec![child(&chain.blocks[1]), child(&chain.blocks[1])];
+        assert!(matches!(chain.reorganize(branch), Err(Error::FinalizedBlock)));
+    }
+
+    #[test]
+    fn two_of_four_are_not_a_quorum()
+    {
+        let gadgets = committee();
+        let mut chain = chain();
+
+        run(&gadgets, 2, &chain, 1);
+
+        assert!(gadgets.iter().all(|gadget| certified(gadget, 1).is_none()));
+        assert_eq!(gadgets[0].apply(&mut chain), None);
+    }
+
+    #[test]
+    fn votes_are_checked_against_the_committee()
+    {
+        let gadgets = committee();
+        let mut chain = chain();
+
+        let vote = gadgets[0].vote(&chain, 1, &chain.blocks[1].hash.clone()).remove(0);
+        assert!(gadgets[1].check_vote(&chain, &vote).is_ok());
+
+        let mut forged = vote.clone();
+        forged.hash = chain.blocks[2].hash.clone();
+        assert!(matches!(gadgets[1].check_vote(&chain, &forged), Err(Error::InvalidSignature)));
+
+        let outsider = FinalityGadget::new(Vec::new(), Some(Keypair::generate()));
+        let key = outsider.key.as_ref().unwrap();
+        let mut stranger = vote.clone();
+        stranger.voter = hex::encode(key.public().to_bytes());
+        stranger.signature = hex::encode(key.sign(vote_message(vote.kind, vote.height, vote.round, &vote.hash).as_bytes()));
+        assert!(matches!(gadgets[1].check_vote(&chain, &stranger), Err(Error::UnauthorizedSigner)));
+
+        let mut ahead = vote.clone();
+        ahead.height = 10;
+        assert!(matches!(gadgets[1].check_vote(&chain, &ahead), Err(Error::OutOfBounds)));
+
+        chain.finalize(1).unwrap();
+        assert!(matches!(gadgets[1].check_vote(&chain, &vote), Err(Error::FinalizedBlock)));
+    }
+}
@@ -0,0 +1,27 @@
+pub mod authority;
+pub mod block;
+pub mod clock;
+pub mod compact;
+pub mod config;
+pub mod consensus;
+pub mod error;
+pub mod events;
+pub mod finality;
+pub mod merkle;
+pub mod miner;
+pub mod mining_server;
+pub mod node;
+pub mod orphan;
+pub mod p2p;
+pub mod payload;
+pub mod pool;
+pub mod ratelimit;
+pub mod rest;
+pub mod rpc;
+pub mod stake;
+pub mod stats;
+pub mod transaction;
+pub mod wire;
+pub mod ws;
+
+pub use node::{ Node, NodeBuilder, NodeHandle };
@@ -0,0 +1,200 @@
+use serde::{ Serialize, Deserialize };
+use sha2::{ Sha256, Digest };
+
+//Leaves and inner nodes are hashed with different prefixes so a leaf can never pass for a subtree.
+const LEAF: &[u8] = b"\x00";
+const NODE: &[u8] = b"\x01";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Side
+{
+    Left,
+    Right,
+}
+
+//One sibling on the way from the leaf to the root, side is where the sibling sits.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProofStep
+{
+    pub hash: String,
+    pub side: Side,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MerkleProof
+{
+    pub leaf: String,
+    pub index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+//Everything a client needs to check a transaction against a block header it already trusts.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransactionProof
+{
+    pub block_hash: String,
+    pub height: u64,
+    pub merkle_root: String,
+    pub proof: MerkleProof,
+}
+
+impl MerkleProof
+{
+    pub fn verify(&self, root: &str) -> bool
+    {
+        let mut hash = hash_leaf(&self.leaf);
+
+        for step in &self.steps
+        {
+            hash = match step.side
+            {
+                Side::Left => hash_node(&step.hash, &hash),
+                Side::Right => hash_node(&hash, &step.hash),
+            };
+        }
+
+        hash == root
+    }
+}
+
+fn hash_leaf(leaf: &str) -> String
+{
+    let mut hasher = Sha256::new();
+    hasher.update(LEAF);
+    hasher.update(leaf);
+
+    hex::encode(hasher.finalize())
+}
+
+fn hash_node(left: &str, right: &str) -> String
+{
+    let mut hasher = Sha256::new();
+    hasher.update(NODE);
+    hasher.update(left);
+    hasher.update(right);
+
+    hex::encode(hasher.finalize())
+}
+
+//Pairs up a level, an odd node at the end moves up unchanged instead of being paired with itself.
+fn next_level(level: &[String]) -> Vec<String>
+{
+    level.chunks(2).map(|pair| match pair
+    {
+        [left, right] => hash_node(left, right),
+        [single] => single.clone(),
+        _ => unreachable!(),
+    }).collect()
+}
+
+//An empty tree has an empty root.
+pub fn merkle_root(leaves: &[String]) -> String
+{
+    if leaves.is_empty()
+    {
+        return String::new();
+    }
+
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+
+    while level.len() > 1
+    {
+        level = next_level(&level);
+    }
+
+    level.remove(0)
+}
+
+pub fn prove(leaves: &[String], index: usize) -> Option<MerkleProof>
+{
+    let leaf = leaves.get(index)?.clone();
+
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    let mut position = index;
+    let mut steps = Vec::new();
+
+    while level.len() > 1
+    {
+        let sibling = position ^ 1;
+
+        if let Some(hash) = level.get(sibling)
+        {
+            let side = if sibling < position { Side::Left } else { Side::Right };
+            steps.push(ProofStep { hash: hash.clone(), side });
+        }
+
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { leaf, index, steps })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String>
+    {
+        (0..count).map(|i| format!("tx{i}")).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root()
+    {
+        for count in [1, 2, 3, 5]
+        {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+
+            for index in 0..count
+            {
+                let proof = prove(&leaves, index).unwrap();
+
+                assert_eq!(proof.leaf, leaves[index]);
+                assert!(proof.verify(&root), "leaf {index} of {count} does not prove");
+            }
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root()
+    {
+        let leaves = leaves(1);
+        let proof = prove(&leaves, 0).unwrap();
+
+        assert!(proof.steps.is_empty());
+        assert_eq!(merkle_root(&leaves), hash_leaf("tx0"));
+    }
+
+    #[test]
+    fn odd_leaf_moves_up_unpaired()
+    {
+        let leaves = leaves(5);
+        let proof = prove(&leaves, 4).unwrap();
+
+        //The fifth leaf has no sibling until the top level, where it meets the root of the first four.
+        assert_eq!(proof.steps.len(), 1);
+        assert_eq!(proof.steps[0].side, Side::Left);
+    }
+
+    #[test]
+    fn tampered_proofs_fail()
+    {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+
+        let mut proof = prove(&leaves, 2).unwrap();
+        proof.leaf = String::from("forged");
+        assert!(!proof.verify(&root));
+
+        let mut proof = prove(&leaves, 2).unwrap();
+        proof.steps[0].side = Side::Left;
+        assert!(!proof.verify(&root));
+
+        assert!(prove(&leaves, 5).is_none());
+        assert!(merkle_root(&[]).is_empty());
+    }
+}
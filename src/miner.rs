@@ -0,0 +1,211 @@
+use chrono::Utc;
+use tokio::sync::mpsc;
+use std::ops::Range;
+use std::time::{ Duration, Instant };
+use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
+
+use crate::block::{ Block, BlockCandidate };
+use crate::consensus::ConsensusEngine;
+use crate::mining_server::MiningServer;
+use crate::payload::Payload;
+use crate::stats::{ Stats, WorkerStats };
+use crate::transaction::{ Mempool, Transaction, MAX_BLOCK_TRANSACTIONS };
+
+const CANDIDATE_REFRESH: Duration = Duration::from_secs(30);
+
+pub struct Miner<P = String>
+{
+    pub enabled: bool,
+    engine: Arc<dyn ConsensusEngine<P>>,
+    tx: mpsc::Sender<Block<P>>,
+    stop_signal: Arc<AtomicBool>,
+    //Set once the current job's workers are hashing, a job still waiting for its slot has done no work yet.
+    started: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    mempool: Arc<Mempool>,
+    server: Option<Arc<MiningServer<P>>>,
+}
+
+impl<P: Payload> Miner<P>
+{
+    pub fn new(enabled: bool, engine: Arc<dyn ConsensusEngine<P>>, tx: mpsc::Sender<Block<P>>, stats: Arc<Stats>, mempool: Arc<Mempool>, server: Option<Arc<MiningServer<P>>>) -> Self
+    {
+        Self
+        {
+            enabled,
+            engine,
+            tx,
+            stop_signal: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(AtomicBool::new(false)),
+            stats,
+            mempool,
+            server,
+        }
+    }
+
+    //Abandons whatever the workers are on and hands out fresh work on top of the new tip. A job the workers were still on
+    //is stale work, one of our own workers already finishing it, or stop, would have flipped the stop signal first.
+    pub fn restart(&mut self, last_block: &Block<P>)
+    {
+        if self.stop_signal.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() && self.started.load(Ordering::SeqCst)
+        {
+            self.stats.record_stale_work();
+        }
+
+        self.stop_signal = Arc::new(AtomicBool::new(false));
+        self.started = Arc::new(AtomicBool::new(false));
+
+        if let Some(server) = &self.server
+        {
+            server.new_template(last_block, self.mempool.select(MAX_BLOCK_TRANSACTIONS));
+        }
+
+        if !self.enabled
+        {
+            return;
+        }
+
+        println!("Mining...");
+        mine_trigger(last_block.clone(), self.engine.clone(), self.tx.clone(), self.stop_signal.clone(), self.started.clone(), self.stats.clone(), self.mempool.clone());
+    }
+
+    pub fn start(&mut self, last_block: &Block<P>)
+    {
+        self.enabled = true;
+        self.restart(last_block);
+    }
+
+    pub fn stop(&mut self)
+    {
+        self.enabled = false;
+        self.stop_signal.store(true, Ordering::SeqCst);
+    }
+}
+
+//Walks the worker's nonce range; when it runs dry the extra nonce is rolled and the timestamp refreshed so the same
+//range can be searched again, and every CANDIDATE_REFRESH the candidate is rebuilt with the newest mempool contents.
+pub fn mine_block<P: Payload>(engine: &dyn ConsensusEngine<P>, mut block_candidate: BlockCandidate<P>, nonces: Range<u64>, stop_signal: &AtomicBool, worker: &WorkerStats, mempool: &Mempool) -> Option<Block<P>>
+{
+    println!("Mining block from nonce {}...", nonces.start);
+    worker.start_job();
+
+    let mut built_at = Instant::now();
+
+    'candidate: loop
+    {
+        let mut batch_start = nonces.start;
+
+        for nonce in nonces.clone()
+        {
+            if nonce % 10000 == 0
+            {
+                worker.add_hashes(nonce - batch_start);
+                batch_start = nonce;
+
+                if stop_signal.load(Ordering::SeqCst)
+                {
+                    println!("Nonce: {nonce}");
+                    return None;
+                }
+
+                if built_at.elapsed() >= CANDIDATE_REFRESH
+                {
+                    println!("Rebuilding candidate at nonce {nonce}...");
+                    rebuild_candidate(&mut block_candidate, mempool);
+                    built_at = Instant::now();
+                    continue 'candidate;
+                }
+            }
+
+            if let Some(block) = engine.seal(&block_candidate, nonce)
+            {
+                worker.add_hashes(nonce + 1 - batch_start);
+
+                println!("
+                Nonce: {nonce},
+                Extra Nonce: {},
+                Hash: {},
+                ", block.extra_nonce, block.hash);
+
+                return Some(block);
+            }
+        }
+
+        worker.add_hashes(nonces.end - batch_start);
+
+        block_candidate.extra_nonce = block_candidate.extra_nonce.wrapping_add(1);
+        block_candidate.timestamp = Utc::now().timestamp().max(block_candidate.timestamp);
+        println!("Nonce range exhausted, rolling extra nonce to {}...", block_candidate.extra_nonce);
+    }
+}
+
+//Splits the nonce space into one range per worker, the first worker to find a solution flips the stop signal for the rest.
+//Engines with sealing turns may hold the workers back until the candidate's slot, or skip it entirely.
+pub fn mine_trigger<P: Payload>(last_block: Block<P>, engine: Arc<dyn ConsensusEngine<P>>, tx: mpsc::Sender<Block<P>>, stop_signal: Arc<AtomicBool>, started: Arc<AtomicBool>, stats: Arc<Stats>, mempool: Arc<Mempool>)
+{
+    let threads = stats.workers.len() as u64;
+
+    let candidate = build_candidate(engine.as_ref(), &last_block, mempool.select(MAX_BLOCK_TRANSACTIONS));
+
+    let Some(delay) = engine.ready_in(&candidate) else
+    {
+        println!("Not our turn to seal block {}, waiting...", candidate.height);
+        return;
+    };
+
+    let span = u64::MAX / threads;
+
+    tokio::spawn(async move
+    {
+        if !delay.is_zero()
+        {
+            println!("Sealing block {} in {}s...", candidate.height, delay.as_secs());
+            tokio::time::sleep(delay).await;
+        }
+
+        if stop_signal.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        started.store(true, Ordering::SeqCst);
+
+        for worker in 0..threads
+        {
+            let start = worker * span;
+            let end = if worker == threads - 1 { u64::MAX } else { start + span };
+
+            let miner_tx = tx.clone();
+            let candidate = candidate.clone();
+            let stop_signal = stop_signal.clone();
+            let stats = stats.clone();
+            let mempool = mempool.clone();
+            let engine = engine.clone();
+
+            tokio::task::spawn_blocking(move ||
+            {
+                match mine_block(engine.as_ref(), candidate, start..end, &stop_signal, &stats.workers[worker as usize], &mempool)
+                {
+                    Some(mined) if stop_signal.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() =>
+                    {
+                        stats.record_block_found();
+                        _ = miner_tx.blocking_send(mined);
+                    }
+                    _ => println!("Worker {worker}: No block has been mined!"),
+                }
+            });
+        }
+    });
+}
+
+pub fn build_candidate<P: Payload>(engine: &dyn ConsensusEngine<P>, last_block: &Block<P>, transactions: Vec<Transaction>) -> BlockCandidate<P>
+{
+    engine.prepare(last_block, P::candidate(), transactions)
+}
+
+fn rebuild_candidate<P: Payload>(block_candidate: &mut BlockCandidate<P>, mempool: &Mempool)
+{
+    block_candidate.set_transactions(mempool.select(MAX_BLOCK_TRANSACTIONS));
+    block_candidate.timestamp = Utc::now().timestamp().max(block_candidate.timestamp);
+    block_candidate.extra_nonce = 0;
+}
@@ -0,0 +1,304 @@
+use serde::{ Serialize, Deserialize };
+use libp2p::futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::watch;
+use tokio_util::codec::{ FramedRead, LinesCodec, LinesCodecError };
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use uuid::Uuid;
+
+use crate::block::{ Block, BlockCandidate, DEFAULT_PREFIX };
+use crate::consensus::ConsensusEngine;
+use crate::miner::build_candidate;
+use crate::node::NodeHandle;
+use crate::payload::Payload;
+use crate::pool::{ Pool, PayoutTable, ShareResult, WorkerShares };
+use crate::stats::Stats;
+use crate::transaction::Transaction;
+
+//Requests are a few hundred bytes, a line that runs past this is not one and the miner is dropped.
+pub const MAX_REQUEST_LENGTH: usize = 16 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound = "P: Payload")]
+pub struct BlockTemplate<P = String>
+{
+    pub job_id: Uuid,
+    pub candidate: BlockCandidate<P>,
+    pub target: String,
+}
+
+//External miners may roll the extra nonce and timestamp themselves, everything else comes from the template.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Submission
+{
+    pub job_id: Uuid,
+    pub nonce: u64,
+    pub extra_nonce: u64,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub worker: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "camelCase")]
+pub enum MiningMethod
+{
+    GetTemplate,
+    Submit(Submission),
+    GetWorkers,
+    GetPayouts,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MiningRequest
+{
+    pub id: u64,
+    #[serde(flatten)]
+    pub method: MiningMethod,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", bound = "P: Payload")]
+pub enum MiningMessage<P = String>
+{
+    Template { id: u64, template: BlockTemplate<P> },
+    Accepted { id: u64, hash: String },
+    ShareAccepted { id: u64, hash: String },
+    Workers { id: u64, workers: Vec<WorkerShares> },
+    Payouts { id: u64, payouts: Vec<PayoutTable> },
+    Rejected { id: u64, reason: String },
+    NewTemplate(BlockTemplate<P>),
+}
+
+//A template's candidate and the tip it was built on, a full solution is checked against that tip.
+#[derive(Clone)]
+struct Job<P>
+{
+    candidate: BlockCandidate<P>,
+    parent: Block<P>,
+}
+
+pub struct MiningServer<P = String>
+{
+    templates: watch::Sender<Option<BlockTemplate<P>>>,
+    jobs: Mutex<HashMap<Uuid, Job<P>>>,
+    stats: Arc<Stats>,
+    pool: Option<Pool>,
+    engine: Arc<dyn ConsensusEngine<P>>,
+}
+
+impl<P: Payload> MiningServer<P>
+{
+    pub fn new(engine: Arc<dyn ConsensusEngine<P>>, stats: Arc<Stats>, pool: Option<Pool>) -> Self
+    {
+        Self
+        {
+            templates: watch::Sender::new(None),
+            jobs: Mutex::new(HashMap::new()),
+            stats,
+            pool,
+            engine,
+        }
+    }
+
+    //Called whenever the tip changes, every job built on the old tip is stale from here on.
+    pub fn new_template(&self, last_block: &Block<P>, transactions: Vec<Transaction>)
+    {
+        let candidate = build_candidate(self.engine.as_ref(), last_block, transactions);
+
+        let template = BlockTemplate
+        {
+            job_id: candidate.index,
+            candidate: candidate.clone(),
+            target: self.pool.as_ref().map_or(DEFAULT_PREFIX.to_owned(), Pool::share_target),
+        };
+
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.clear();
+            jobs.insert(template.job_id, Job { candidate, parent: last_block.clone() });
+        }
+
+        if let Some(pool) = &self.pool
+        {
+            pool.new_job();
+        }
+
+        self.templates.send_replace(Some(template));
+    }
+
+    pub fn current_template(&self) -> Option<BlockTemplate<P>>
+    {
+        self.templates.borrow().clone()
+    }
+
+    //In pool mode a submission only has to meet the share target, full solutions are passed on to the chain.
+    pub fn submit(&self, submission: Submission) -> Result<ShareResult<P>, String>
+    {
+        let Job { mut candidate, parent } = self.jobs.lock().unwrap().get(&submission.job_id).cloned().ok_or("Stale or unknown job")?;
+
+        candidate.extra_nonce = submission.extra_nonce;
+        candidate.timestamp = submission.timestamp;
+
+        let hash = candidate.hash(submission.nonce);
+        let block = candidate.into_block(hash, submission.nonce);
+
+        let block = match &self.pool
+        {
+            Some(pool) =>
+            {
+                let worker = submission.worker.as_deref().unwrap_or("anonymous");
+
+                match pool.submit_share(worker, submission.job_id, block)?
+                {
+                    ShareResult::Block(block) => *block,
+                    share => return Ok(share),
+                }
+            }
+            None => block,
+        };
+
+        self.engine.verify_seal(&block, &parent).map_err(|e| e.to_string())?;
+
+        Ok(ShareResult::Block(Box::new(block)))
+    }
+
+    //Only a block the chain took counts as found, and only then does the pool pay out its round.
+    pub fn block_accepted(&self, hash: &str)
+    {
+        self.stats.record_block_found();
+
+        if let Some(pool) = &self.pool
+        {
+            pool.confirm_round(hash);
+        }
+    }
+
+    pub fn block_rejected(&self, hash: &str)
+    {
+        if let Some(pool) = &self.pool
+        {
+            pool.discard_round(hash);
+        }
+    }
+
+    pub fn workers(&self) -> Option<Vec<WorkerShares>>
+    {
+        self.pool.as_ref().map(Pool::workers)
+    }
+
+    pub fn payouts(&self) -> Option<Vec<PayoutTable>>
+    {
+        self.pool.as_ref().map(Pool::payouts)
+    }
+}
+
+pub fn serve<P: Payload>(listener: TcpListener, server: Arc<MiningServer<P>>, node: NodeHandle<P>)
+{
+    tokio::spawn(async move
+    {
+        loop
+        {
+            match listener.accept().await
+            {
+                Ok((socket, addr)) =>
+                {
+                    println!("External miner connected from {addr}");
+                    tokio::spawn(handle_miner(socket, server.clone(), node.clone()));
+                }
+                Err(e) => println!("Mining server accept failed! {e}"),
+            }
+        }
+    });
+}
+
+async fn handle_miner<P: Payload>(socket: TcpStream, server: Arc<MiningServer<P>>, node: NodeHandle<P>)
+{
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_REQUEST_LENGTH));
+    let mut templates = server.templates.subscribe();
+
+    loop
+    {
+        let message = tokio::select!
+        {
+            line = lines.next() =>
+            {
+                let line = match line
+                {
+                    Some(Ok(line)) => line,
+                    Some(Err(LinesCodecError::MaxLineLengthExceeded)) =>
+                    {
+                        println!("External miner sent a request over {MAX_REQUEST_LENGTH} bytes, disconnecting");
+                        break;
+                    }
+                    _ => break,
+                };
+
+                match serde_json::from_str::<MiningRequest>(&line)
+                {
+                    Ok(MiningRequest { id, method: MiningMethod::GetTemplate }) => match server.current_template()
+                    {
+                        Some(template) => MiningMessage::Template { id, template },
+                        None => MiningMessage::Rejected { id, reason: String::from("No template available") },
+                    },
+                    Ok(MiningRequest { id, method: MiningMethod::Submit(submission) }) => match server.submit(submission)
+                    {
+                        //Answered once the chain has run add_block on it, a solution can still lose to another block.
+                        Ok(ShareResult::Block(block)) =>
+                        {
+                            let hash = block.hash.clone();
+
+                            match node.submit_block(*block).await
+                            {
+                                Ok(()) =>
+                                {
+                                    server.block_accepted(&hash);
+                                    MiningMessage::Accepted { id, hash }
+                                }
+                                Err(e) =>
+                                {
+                                    server.block_rejected(&hash);
+                                    MiningMessage::Rejected { id, reason: e.to_string() }
+                                }
+                            }
+                        }
+                        Ok(ShareResult::Share(hash)) => MiningMessage::ShareAccepted { id, hash },
+                        Err(reason) => MiningMessage::Rejected { id, reason },
+                    },
+                    Ok(MiningRequest { id, method: MiningMethod::GetWorkers }) => match server.workers()
+                    {
+                        Some(workers) => MiningMessage::Workers { id, workers },
+                        None => MiningMessage::Rejected { id, reason: String::from("Pool mode is disabled") },
+                    },
+                    Ok(MiningRequest { id, method: MiningMethod::GetPayouts }) => match server.payouts()
+                    {
+                        Some(payouts) => MiningMessage::Payouts { id, payouts },
+                        None => MiningMessage::Rejected { id, reason: String::from("Pool mode is disabled") },
+                    },
+                    Err(e) => MiningMessage::Rejected { id: 0, reason: e.to_string() },
+                }
+            }
+            Ok(()) = templates.changed() =>
+            {
+                match templates.borrow_and_update().clone()
+                {
+                    Some(template) => MiningMessage::NewTemplate(template),
+                    None => continue,
+                }
+            }
+        };
+
+        let Ok(mut serialized) = serde_json::to_vec(&message) else { continue };
+        serialized.push(b'\n');
+
+        if writer.write_all(&serialized).await.is_err()
+        {
+            break;
+        }
+    }
+
+    println!("External miner disconnected");
+}
@@ -0,0 +1,1914 @@
+use libp2p::{ noise,
+    identity::ed25519::Keypair,
+    tcp,
+    yamux,
+    ping,
+    gossipsub,
+    futures::StreamExt,
+    gossipsub::{MessageAcceptance, MessageAuthenticity, MessageId, IdentTopic, TopicHash},
+    swarm::{SwarmEvent, dial_opts::{DialOpts, PeerCondition}},
+    mdns,
+    request_response::{ self, OutboundRequestId, ResponseChannel },
+    PeerId,
+    Multiaddr,
+    multiaddr::Protocol,
+    Swarm,
+};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::{ broadcast, mpsc, oneshot, RwLock, RwLockReadGuard };
+use tokio::task::JoinHandle;
+use std::collections::{ HashMap, VecDeque };
+use std::num::NonZeroU8;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use crate::block::{ BlockState, Block, BlockHeader, MAX_BLOCK_SIZE, MAX_FUTURE_DRIFT };
+use crate::clock::NetworkClock;
+use crate::compact::{ CompactBlock, PartialBlock };
+use crate::authority::{ self, ProofOfAuthority };
+use crate::config::{ Config, Consensus, prefer_quic };
+use crate::consensus::{ ConsensusEngine, ConsensusRecord, ProofOfWork };
+use crate::error::Error;
+use crate::events::NodeEvent;
+use crate::finality::{ FinalityGadget, FinalityVote };
+use crate::merkle::TransactionProof;
+use crate::miner::Miner;
+use crate::orphan::OrphanPool;
+use crate::payload::Payload;
+use crate::mining_server::{ self, MiningServer };
+use crate::p2p::{AppBehaviour, Event as MainEvent, BlockRequest, BlockResponse, SyncCodec, is_over_size_cap, sync_protocols, MAX_HEADERS, MAX_GOSSIP_SIZE, MAX_RESPONSE_SIZE, MAX_SMALL_MESSAGE_SIZE};
+use crate::pool::Pool;
+use crate::ratelimit::RateLimiter;
+use crate::stake::ProofOfStake;
+use crate::stats::Stats;
+use crate::transaction::{ Mempool, Transaction };
+use crate::wire::{ self, Encoding };
+use crate::{ rest, rpc, ws };
+
+const MAX_FORK_DEPTH: usize = 100;
+//How often a light client asks its peers for new headers, full nodes get them through gossip instead.
+const HEADER_SYNC_INTERVAL: u64 = 10;
+//Compact blocks waiting on transactions from a peer, past this they are dropped and left to sync.
+const MAX_PARTIAL_BLOCKS: usize = 16;
+//Blocks from too far ahead wait here, the ones furthest out are dropped first once it's full.
+const MAX_FUTURE_BLOCKS: usize = 16;
+const FUTURE_RETRY_INTERVAL: u64 = 1;
+//Misbehaviour points, a peer that reaches BAN_SCORE is disconnected and not let back in for BAN_DURATION. Points
+//wear off at SCORE_DECAY per SCORE_DECAY_INTERVAL, so only a steady stream of junk gets a peer banned.
+const OVERSIZED_PENALTY: u32 = 50;
+const SCORE_DECAY: u32 = 10;
+const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+//Inbound sync requests wait in a queue that is drained a batch at a time, a full queue throttles everyone.
+const MAX_QUEUED_REQUESTS: usize = 256;
+const MAX_RESPONSES_PER_TICK: usize = 32;
+const RESPONSE_TICK_MILLIS: u64 = 10;
+const QUEUE_FULL_RETRY: Duration = Duration::from_secs(1);
+//A peer that throttles us can't park our requests for longer than this.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+const BAN_SCORE: u32 = 100;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PeerInfo
+{
+    pub peer_id: String,
+    pub address: String,
+}
+
+//Requests that need the swarm or the miner, which only the node's event loop owns.
+#[derive(Debug)]
+pub enum NodeCommand<P = String>
+{
+    GetPeerInfo(oneshot::Sender<Vec<PeerInfo>>),
+    SubmitTransaction(Transaction, oneshot::Sender<Result<bool, Error>>),
+    SubmitBlock(Block<P>, oneshot::Sender<Result<(), Error>>),
+    VerifyTransaction(String, oneshot::Sender<Result<TransactionProof, Error>>),
+    StartMining(oneshot::Sender<bool>),
+    StopMining(oneshot::Sender<bool>),
+    Shutdown,
+}
+
+pub struct NodeBuilder<P = String>
+{
+    config: Config,
+    engine: Option<Arc<dyn ConsensusEngine<P>>>,
+}
+
+impl<P: Payload> Default for NodeBuilder<P>
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            config: Config::default(),
+            engine: None,
+        }
+    }
+}
+
+//A node carrying the default String payload, NodeBuilder::<P>::default() starts one for any other payload.
+impl NodeBuilder
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+}
+
+impl<P: Payload> NodeBuilder<P>
+{
+    pub fn config(mut self, config: Config) -> Self
+    {
+        self.config = config;
+        self
+    }
+
+    //Overrides the engine picked by the config.
+    pub fn engine(mut self, engine: Arc<dyn ConsensusEngine<P>>) -> Self
+    {
+        self.engine = Some(engine);
+        self
+    }
+
+    pub fn file_path(mut self, path: impl Into<String>) -> Self
+    {
+        self.config.file_path = path.into();
+        self
+    }
+
+    pub fn mining(mut self, mining: bool) -> Self
+    {
+        self.config.mining = mining;
+        self
+    }
+
+    pub fn mining_threads(mut self, threads: usize) -> Self
+    {
+        self.config.mining_threads = threads;
+        self
+    }
+
+    pub fn listen(mut self, addr: Multiaddr) -> Self
+    {
+        if crate::config::is_quic(&addr)
+        {
+            self.config.quic_listen = vec![addr];
+        }
+        else
+        {
+            self.config.tcp_listen = vec![addr];
+        }
+
+        self
+    }
+
+    pub fn dial(mut self, addr: Multiaddr) -> Self
+    {
+        self.config.dial.push(addr);
+        self
+    }
+
+    //Sets up the swarm, loads the chain and any configured servers, then runs the event loop on its own task.
+    pub async fn start(self) -> Result<Node<P>, Error>
+    {
+        let config = self.config;
+
+        //Embedders hand over a Config directly, so the loopback rule from the command line is checked again here.
+        if config.rpc.is_some_and(|addr| !addr.ip().is_loopback())
+        {
+            return Err(Error::InvalidArgument(String::from("JSON-RPC can only be served on a loopback address")));
+        }
+
+        if config.mining_server.is_some() && config.consensus != Consensus::ProofOfWork
+        {
+            return Err(Error::InvalidArgument(String::from("Block templates can only be served for proof of work")));
+        }
+
+        let engine = match self.engine
+        {
+            Some(engine) => engine,
+            None => build_engine(&config)?,
+        };
+
+        let stats = Arc::new(Stats::new(config.mining_threads));
+
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity() .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            ).unwrap()
+            .with_quic()
+            .with_behaviour(|key|
+            {
+                //Messages are only forwarded once we validated them, so whoever hands us junk is the one it came from.
+                let gossipsub_config = gossipsub::ConfigBuilder::default().max_transmit_size(MAX_GOSSIP_SIZE).validate_messages().build().expect("Gossipsub config failed");
+                let gossipsub = gossipsub::Behaviour::new(MessageAuthenticity::Signed(key.clone()), gossipsub_config).expect("Gossipsub failed");
+
+                let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id()).expect("Mdns failed");
+
+                let req_resp = request_response::Behaviour::with_codec(
+                        SyncCodec::new(stats.clone()),
+                        sync_protocols(config.wire),
+                        request_response::Config::default(),
+                    );
+                AppBehaviour
+                {
+                    gossipsub,
+                    ping: ping::Behaviour::default(),
+                    mdns,
+                    request_response: req_resp,
+
+                }
+            })?
+            .with_swarm_config(|cfg|
+            {
+                cfg.with_idle_connection_timeout(std::time::Duration::from_secs(u64::MAX))
+            })
+            .build();
+
+        let topic = IdentTopic::new("Blockchain");
+        let transaction_topic = IdentTopic::new("Transactions");
+        let finality_topic = IdentTopic::new("Finality");
+
+        //Light clients pull headers instead of having full blocks pushed to them.
+        if !config.light
+        {
+            swarm.behaviour_mut().gossipsub.subscribe(&topic).expect("Topic subscription failed");
+            swarm.behaviour_mut().gossipsub.subscribe(&transaction_topic).expect("Topic subscription failed");
+            swarm.behaviour_mut().gossipsub.subscribe(&finality_topic).expect("Topic subscription failed");
+        }
+
+        for addr in config.tcp_listen.iter().chain(config.quic_listen.iter())
+        {
+            swarm.listen_on(addr.clone())?;
+        }
+
+        //Addresses of the same peer are dialed together so QUIC gets tried before TCP, the rest one by one.
+        let mut dial_peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+
+        for remote in &config.dial
+        {
+            match remote.iter().last()
+            {
+                Some(Protocol::P2p(peer_id)) => dial_peers.entry(peer_id).or_default().push(remote.clone()),
+                _ => match swarm.dial(remote.clone())
+                {
+                    Ok(_) => println!("Dialed {remote}"),
+                    Err(e) => println!("Failed to dial {remote}: {e}"),
+                },
+            }
+        }
+
+        for (peer_id, addrs) in dial_peers
+        {
+            match swarm.dial(dial_opts(peer_id, addrs))
+            {
+                Ok(_) => println!("Dialed {peer_id}"),
+                Err(e) => println!("Failed to dial {peer_id}: {e}"),
+            }
+        }
+
+        if config.light
+        {
+            println!("Deploying Blockchain as a light client, syncing headers only...\n");
+        }
+        else if config.mining
+        {
+            println!("Deploying Blockchain...\n");
+        }
+        else
+        {
+            println!("Deploying Blockchain in relay-only mode, mining is disabled...\n");
+        }
+
+        let mut find_chain = match BlockState::load_file(&config.file_path)
+        {
+            Ok(k) => k,
+            Err(e) =>
+            {
+                println!("File error! {}", e);
+                BlockState::new()
+            }
+        };
+
+        if find_chain.light != config.light && find_chain.blocks.len() > 1
+        {
+            return Err(Error::InvalidArgument(format!("{} was synced {} --light", config.file_path, if find_chain.light { "with" } else { "without" })));
+        }
+
+        find_chain.light = config.light;
+        find_chain.set_engine(engine.clone());
+
+        if find_chain.blocks.is_empty()
+        {
+            find_chain.create_genesis_block();
+
+            if let Err(e) = find_chain.save_to_file(&config.file_path)
+            {
+                println!("Failed to save file! {e}");
+            }
+        }
+
+        for (height, hash) in &config.checkpoints
+        {
+            find_chain.add_checkpoint(*height, hash.clone()).map_err(|_| Error::InvalidArgument(format!("{} disagrees with checkpoint {height}:{hash}", config.file_path)))?;
+        }
+
+        if let Some((height, hash)) = &config.assume_valid
+        {
+            find_chain.set_assume_valid(*height, hash.clone()).map_err(|_| Error::InvalidArgument(format!("{} disagrees with --assume-valid {height}:{hash}", config.file_path)))?;
+        }
+
+        let chain = Arc::new(RwLock::new(find_chain));
+
+        let (tx, rx) = mpsc::channel::<Block<P>>(100);
+        let (command_tx, command_rx) = mpsc::channel::<NodeCommand<P>>(100);
+        let (events, _) = broadcast::channel::<NodeEvent<P>>(256);
+        let mempool = Arc::new(Mempool::new());
+
+        let handle = NodeHandle
+        {
+            chain: chain.clone(),
+            mempool: mempool.clone(),
+            stats: stats.clone(),
+            commands: command_tx,
+            events: events.clone(),
+        };
+
+        let mining_server = match config.mining_server
+        {
+            Some(addr) =>
+            {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let pool = config.pool.then(|| Pool::new(config.share_difficulty, config.pplns_window));
+                let server = Arc::new(MiningServer::new(engine.clone(), stats.clone(), pool));
+
+                mining_server::serve(listener, server.clone(), handle.clone());
+                println!("Serving block templates on {addr}");
+
+                if !addr.ip().is_loopback()
+                {
+                    println!("The mining server has no authentication and is reachable from other hosts on {addr}");
+                }
+
+                Some(server)
+            }
+            None => None,
+        };
+
+        if let Some(addr) = config.rpc
+        {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+
+            rpc::serve(listener, handle.clone());
+            println!("Serving JSON-RPC on {addr}");
+        }
+
+        if let Some(addr) = config.rest
+        {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+
+            rest::serve(listener, handle.clone());
+            println!("Serving REST API on {addr}");
+        }
+
+        if let Some(addr) = config.ws
+        {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+
+            ws::serve(listener, handle.clone());
+            println!("Serving WebSocket events on {addr}/ws");
+        }
+
+        let finality = if config.finality
+        {
+            Some(FinalityGadget::new(config.validators.clone(), validator_key(&config)?))
+        }
+        else
+        {
+            None
+        };
+
+        let mut miner = Miner::new(config.mining, engine, tx, stats.clone(), mempool.clone(), mining_server);
+
+        {
+            let chain_lock = chain.read().await;
+            miner.restart(chain_lock.blocks.last().unwrap());
+        }
+
+        let runtime = Runtime
+        {
+            swarm,
+            chain,
+            mempool,
+            miner,
+            stats,
+            events,
+            topic,
+            transaction_topic,
+            finality_topic,
+            finality,
+            light: config.light,
+            wire: config.wire,
+            peers: HashMap::new(),
+            forks: HashMap::new(),
+            partials: HashMap::new(),
+            orphans: OrphanPool::new(),
+            future: Vec::new(),
+            clock: NetworkClock::new(),
+            proofs: HashMap::new(),
+            misbehaviour: HashMap::new(),
+            banned: HashMap::new(),
+            limiter: RateLimiter::new(),
+            requests: VecDeque::new(),
+            retries: Vec::new(),
+            outbound: HashMap::new(),
+            file_path: config.file_path,
+        };
+
+        let task = tokio::spawn(runtime.run(rx, command_rx, config.stats_interval));
+
+        Ok(Node { handle, task })
+    }
+}
+
+//A misbehaviour score worn down by the time since it was last updated.
+fn decayed(score: u32, updated: Instant, now: Instant) -> u32
+{
+    let intervals = (now - updated).as_secs() / SCORE_DECAY_INTERVAL.as_secs();
+
+    score.saturating_sub(intervals.min(u32::MAX as u64) as u32 * SCORE_DECAY)
+}
+
+//Dials a peer one address at a time, the swarm would otherwise race them all and the QUIC first order would mean nothing.
+fn dial_opts(peer_id: PeerId, addrs: Vec<Multiaddr>) -> DialOpts
+{
+    DialOpts::peer_id(peer_id)
+        .addresses(prefer_quic(addrs))
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .override_dial_concurrency_factor(NonZeroU8::new(1).unwrap())
+        .build()
+}
+
+fn validator_key(config: &Config) -> Result<Option<Keypair>, Error>
+{
+    config.validator_key.as_deref().map(authority::parse_key).transpose()
+}
+
+fn build_engine<P: Payload>(config: &Config) -> Result<Arc<dyn ConsensusEngine<P>>, Error>
+{
+    if config.consensus == Consensus::ProofOfWork
+    {
+        return Ok(Arc::new(ProofOfWork::default()));
+    }
+
+    for validator in &config.validators
+    {
+        authority::check_public_key(validator)?;
+    }
+
+    let key = validator_key(config)?;
+    let public = key.as_ref().map(|key| hex::encode(key.public().to_bytes()));
+
+    match &public
+    {
+        Some(public) if config.validators.contains(public) => println!("Validating as {public}"),
+        Some(public) => println!("{public} is not in the validator set yet, following the chain"),
+        None => println!("No validator key, following the chain"),
+    }
+
+    if config.consensus == Consensus::ProofOfStake
+    {
+        let stakes = config.validators.iter().map(|validator| (validator.clone(), config.genesis_stake)).collect();
+        return Ok(Arc::new(ProofOfStake::new(stakes, config.slot_time, config.epoch_length, key)));
+    }
+
+    Ok(Arc::new(ProofOfAuthority::new(config.validators.clone(), config.slot_time, key)))
+}
+
+pub struct Node<P: Payload = String>
+{
+    handle: NodeHandle<P>,
+    task: JoinHandle<()>,
+}
+
+impl<P: Payload> Node<P>
+{
+    pub fn handle(&self) -> NodeHandle<P>
+    {
+        self.handle.clone()
+    }
+
+    //Resolves once the event loop exits, either through stop() or a shutdown request.
+    pub async fn wait(self) -> Result<(), Error>
+    {
+        self.task.await.map_err(|_| Error::NodeStopped)
+    }
+
+    pub async fn stop(self) -> Result<(), Error>
+    {
+        self.handle.shutdown().await?;
+        self.wait().await
+    }
+}
+
+#[derive(Clone)]
+pub struct NodeHandle<P: Payload = String>
+{
+    chain: Arc<RwLock<BlockState<P>>>,
+    mempool: Arc<Mempool>,
+    stats: Arc<Stats>,
+    commands: mpsc::Sender<NodeCommand<P>>,
+    events: broadcast::Sender<NodeEvent<P>>,
+}
+
+impl<P: Payload> NodeHandle<P>
+{
+    //Read access to the chain, held until the guard is dropped.
+    pub async fn chain(&self) -> RwLockReadGuard<'_, BlockState<P>>
+    {
+        self.chain.read().await
+    }
+
+    pub fn mempool(&self) -> Arc<Mempool>
+    {
+        self.mempool.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats>
+    {
+        self.stats.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent<P>>
+    {
+        self.events.subscribe()
+    }
+
+    pub async fn finalized_height(&self) -> u64
+    {
+        self.chain.read().await.finalized
+    }
+
+    pub async fn height(&self) -> u64
+    {
+        self.chain.read().await.blocks.last().map_or(0, |block| block.height)
+    }
+
+    pub async fn tip(&self) -> Option<Block<P>>
+    {
+        self.chain.read().await.blocks.last().cloned()
+    }
+
+    pub async fn block(&self, height: u64) -> Option<Block<P>>
+    {
+        self.chain.read().await.blocks.get(height as usize).cloned()
+    }
+
+    pub async fn block_by_hash(&self, hash: &str) -> Option<Block<P>>
+    {
+        self.chain.read().await.find_block(hash).cloned()
+    }
+
+    pub async fn transaction_proof(&self, id: &str) -> Option<TransactionProof>
+    {
+        self.chain.read().await.transaction_proof(id)
+    }
+
+    //A light client asks a full node for the proof and checks it against its own headers.
+    pub async fn verify_transaction(&self, id: &str) -> Result<TransactionProof, Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::VerifyTransaction(id.to_owned(), reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::GetPeerInfo(reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)
+    }
+
+    //Returns false when the transaction was already pending.
+    pub async fn submit_transaction(&self, transaction: Transaction) -> Result<bool, Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::SubmitTransaction(transaction, reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    pub async fn submit_block(&self, block: Block<P>) -> Result<(), Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::SubmitBlock(block, reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    //Queues a consensus record such as a validator vote for the blocks this node seals.
+    pub async fn submit_record(&self, record: ConsensusRecord) -> Result<(), Error>
+    {
+        self.chain.read().await.engine().submit_record(record)
+    }
+
+    pub async fn validators(&self) -> Vec<String>
+    {
+        let chain = self.chain.read().await;
+
+        chain.blocks.last().map(|tip| chain.engine().validators(tip)).unwrap_or_default()
+    }
+
+    //Both return whether the miner was running before the call.
+    pub async fn start_mining(&self) -> Result<bool, Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::StartMining(reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)
+    }
+
+    pub async fn stop_mining(&self) -> Result<bool, Error>
+    {
+        let (reply, response) = oneshot::channel();
+        self.send(NodeCommand::StopMining(reply)).await?;
+
+        response.await.map_err(|_| Error::NodeStopped)
+    }
+
+    pub async fn shutdown(&self) -> Result<(), Error>
+    {
+        self.send(NodeCommand::Shutdown).await
+    }
+
+    async fn send(&self, command: NodeCommand<P>) -> Result<(), Error>
+    {
+        self.commands.send(command).await.map_err(|_| Error::NodeStopped)
+    }
+}
+
+//A light client's proof request, passed on to the next peer until one of them answers it.
+struct ProofRequest
+{
+    id: String,
+    reply: oneshot::Sender<Result<TransactionProof, Error>>,
+    asked: Vec<PeerId>,
+    //What to answer with once every peer has been asked.
+    error: Error,
+}
+
+struct Runtime<P: Payload>
+{
+    swarm: Swarm<AppBehaviour<P>>,
+    chain: Arc<RwLock<BlockState<P>>>,
+    mempool: Arc<Mempool>,
+    miner: Miner<P>,
+    stats: Arc<Stats>,
+    events: broadcast::Sender<NodeEvent<P>>,
+    topic: IdentTopic,
+    transaction_topic: IdentTopic,
+    finality_topic: IdentTopic,
+    finality: Option<FinalityGadget>,
+    light: bool,
+    wire: Encoding,
+    peers: HashMap<PeerId, Multiaddr>,
+    forks: HashMap<PeerId, Vec<Block<P>>>,
+    partials: HashMap<String, PartialBlock<P>>,
+    orphans: OrphanPool<P>,
+    future: Vec<(Block<P>, Option<PeerId>)>,
+    clock: NetworkClock,
+    proofs: HashMap<OutboundRequestId, ProofRequest>,
+    //Score and when it was last brought up to date.
+    misbehaviour: HashMap<PeerId, (u32, Instant)>,
+    //When each ban runs out.
+    banned: HashMap<PeerId, Instant>,
+    limiter: RateLimiter<(PeerId, &'static str)>,
+    requests: VecDeque<(PeerId, BlockRequest, ResponseChannel<BlockResponse<P>>)>,
+    retries: Vec<(Instant, PeerId, BlockRequest)>,
+    //Our requests still waiting on a response, a throttled one is sent again as we made it.
+    outbound: HashMap<OutboundRequestId, BlockRequest>,
+    file_path: String,
+}
+
+impl<P: Payload> Runtime<P>
+{
+    async fn run(mut self, mut rx: mpsc::Receiver<Block<P>>, mut command_rx: mpsc::Receiver<NodeCommand<P>>, stats_interval: u64)
+    {
+        let mut summary = tokio::time::interval(std::time::Duration::from_secs(stats_interval));
+        let mut header_sync = tokio::time::interval(std::time::Duration::from_secs(HEADER_SYNC_INTERVAL));
+        let mut response_tick = tokio::time::interval(Duration::from_millis(RESPONSE_TICK_MILLIS));
+        response_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut future_retry = tokio::time::interval(Duration::from_secs(FUTURE_RETRY_INTERVAL));
+
+        loop
+        {
+            tokio::select!
+            {
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event).await,
+                Some(new_block) = rx.recv() =>
+                {
+                    println!("Miner has found a new block! {:?}", &new_block.hash);
+
+                    if let Err(e) = self.publish_block(new_block).await
+                    {
+                        println!("An error has occured! {e}");
+                    }
+                }
+                Some(command) = command_rx.recv() =>
+                {
+                    if !self.handle_command(command).await
+                    {
+                        break;
+                    }
+                }
+                _ = response_tick.tick(), if !self.requests.is_empty() || !self.retries.is_empty() =>
+                {
+                    self.serve_requests().await;
+                    self.send_retries();
+                }
+                _ = future_retry.tick(), if !self.future.is_empty() => self.retry_future_blocks().await,
+                _ = header_sync.tick(), if self.light =>
+                {
+                    let next_height = self.chain.read().await.blocks.len() as u64;
+                    let peers: Vec<PeerId> = self.peers.keys().cloned().collect();
+
+                    for peer in peers
+                    {
+                        self.request_headers(&peer, next_height);
+                    }
+                }
+                _ = summary.tick() =>
+                {
+                    let chain_lock = self.chain.read().await;
+                    println!("\n{}", self.stats.report(&chain_lock));
+                }
+            }
+        }
+
+        let chain_lock = self.chain.read().await;
+        self.save(&chain_lock);
+    }
+
+    fn save(&self, chain: &BlockState<P>)
+    {
+        if let Err(e) = chain.save_to_file(&self.file_path)
+        {
+            println!("Failed to save file {e}");
+        }
+    }
+
+    //Everything that has to follow once a block lands on top of the chain.
+    fn on_new_tip(&mut self, chain: &mut BlockState<P>)
+    {
+        self.announce(chain.blocks.last().unwrap());
+        self.connect_orphans(chain);
+        self.save(chain);
+
+        self.miner.restart(chain.blocks.last().unwrap());
+        self.vote_finality(chain);
+    }
+
+    //A transaction that is already on the chain could otherwise be gossiped back in and mined a second time.
+    fn add_to_mempool(&self, chain: &BlockState<P>, transaction: Transaction) -> Result<bool, Error>
+    {
+        if chain.is_confirmed(&transaction.id)
+        {
+            return Ok(false);
+        }
+
+        self.mempool.add(transaction)
+    }
+
+    fn announce(&mut self, block: &Block<P>)
+    {
+        self.mempool.remove_included(&block.transactions);
+        _ = self.events.send(NodeEvent::NewBlock(block.clone()));
+    }
+
+    //Orphans waiting on the tip join the chain, each one may free its own children in turn. Once none do, a competing
+    //branch in the pool that has grown heavier than ours takes over.
+    fn connect_orphans(&mut self, chain: &mut BlockState<P>)
+    {
+        while self.connect_orphan(chain) || self.reorganize_to_orphans(chain) {}
+    }
+
+    fn connect_orphan(&mut self, chain: &mut BlockState<P>) -> bool
+    {
+        let tip = chain.blocks.last().unwrap().hash.clone();
+
+        for orphan in self.orphans.children(&tip)
+        {
+            self.orphans.remove(&orphan.hash);
+
+            match chain.add_block(orphan.clone())
+            {
+                Ok(()) =>
+                {
+                    println!("Connected orphan block at height {}", chain.blocks.len() - 1);
+                    self.announce(chain.blocks.last().unwrap());
+                    return true;
+                },
+                Err(Error::TimestampTooFarAhead) => self.hold_future(orphan, None),
+                Err(e) => println!("Dropping orphan block! {e}"),
+            }
+        }
+
+        false
+    }
+
+    //Branches in the pool that fork off below our tip are offered to fork choice, which turns down the ones that
+    //don't outweigh ours before checking any of their blocks.
+    fn reorganize_to_orphans(&mut self, chain: &mut BlockState<P>) -> bool
+    {
+        let roots: Vec<String> = self.orphans.iter()
+            .filter(|block| block.height > chain.finalized && (block.height as usize) < chain.blocks.len())
+            .filter(|block| chain.blocks[block.height as usize - 1].hash == block.previous_hash)
+            .map(|block| block.hash.clone())
+            .collect();
+
+        for root in roots
+        {
+            let branch = self.orphans.longest_branch(&root);
+            let height = branch[0].height;
+
+            match chain.reorganize(branch.clone())
+            {
+                Ok(removed) =>
+                {
+                    println!("Reorganized! {} blocks replaced from height {height} by held orphans", removed.len());
+
+                    for block in &branch
+                    {
+                        self.orphans.remove(&block.hash);
+                    }
+
+                    self.record_reorg(chain, removed, branch);
+                    return true;
+                },
+                Err(Error::ChainTooShort) => {},
+                Err(Error::TimestampTooFarAhead) =>
+                {
+                    for block in &branch
+                    {
+                        self.orphans.remove(&block.hash);
+                    }
+
+                    self.hold_branch(chain, branch, None);
+                },
+                Err(e) =>
+                {
+                    println!("Dropping orphan branch! {e}");
+
+                    for block in &branch
+                    {
+                        self.orphans.remove(&block.hash);
+                    }
+                },
+            }
+        }
+
+        false
+    }
+
+    //Transactions from the blocks we dropped go back to the mempool unless the new branch has them too.
+    fn record_reorg(&mut self, chain: &BlockState<P>, removed: Vec<Block<P>>, added: Vec<Block<P>>)
+    {
+        self.stats.record_orphans(removed.len());
+
+        for transaction in removed.iter().flat_map(|block| block.transactions.iter())
+        {
+            _ = self.add_to_mempool(chain, transaction.clone());
+        }
+
+        for block in &added
+        {
+            self.mempool.remove_included(&block.transactions);
+        }
+
+        _ = self.events.send(NodeEvent::Reorg { removed, added });
+    }
+
+    //Everything that has to follow once a reorganization hands us a new tip.
+    fn on_reorg(&mut self, chain: &mut BlockState<P>)
+    {
+        self.connect_orphans(chain);
+        self.save(chain);
+        self.miner.restart(chain.blocks.last().unwrap());
+        self.vote_finality(chain);
+    }
+
+    fn hold_future(&mut self, block: Block<P>, source: Option<PeerId>)
+    {
+        println!("Block at height {} is from {}s ahead, holding it until then", block.height, block.timestamp - Utc::now().timestamp());
+
+        self.future.push((block, source));
+        self.future.sort_by_key(|(block, _)| block.timestamp);
+        self.future.truncate(MAX_FUTURE_BLOCKS);
+    }
+
+    //A branch that reaches past the drift waits in the orphan pool, with the blocks from the future held back until
+    //their time comes. The last of them to come back sets fork choice going again.
+    fn hold_branch(&mut self, chain: &BlockState<P>, branch: Vec<Block<P>>, source: Option<PeerId>)
+    {
+        let cutoff = chain.network_time() + MAX_FUTURE_DRIFT;
+
+        for block in branch
+        {
+            if block.timestamp > cutoff
+            {
+                self.hold_future(block, source);
+            }
+            else if chain.engine().verify_header(&block).is_ok()
+            {
+                self.orphans.insert(block);
+            }
+        }
+    }
+
+    //Blocks that came back within the drift go through the gossip path again. One that came from sync carries on
+    //syncing from its peer once it lands.
+    async fn retry_future_blocks(&mut self)
+    {
+        let cutoff = self.chain.read().await.network_time() + MAX_FUTURE_DRIFT;
+        let ready = self.future.iter().take_while(|(block, _)| block.timestamp <= cutoff).count();
+
+        for (block, source) in self.future.drain(..ready).collect::<Vec<_>>()
+        {
+            let height = block.height as usize;
+            self.handle_gossiped_block(block, source).await;
+
+            let next_height = self.chain.read().await.blocks.len();
+
+            if let Some(peer) = source.filter(|_| next_height > height)
+            {
+                self.request_block(&peer, next_height as u64);
+            }
+        }
+    }
+
+    async fn adjust_clock(&mut self)
+    {
+        let offset = self.clock.offset();
+        let mut chain_lock = self.chain.write().await;
+
+        if chain_lock.time_offset() != offset
+        {
+            println!("Network time is {offset}s off from our clock");
+        }
+
+        chain_lock.set_time_offset(offset);
+    }
+
+    //Blocks beyond our tip are kept until their ancestors show up, blocks on a competing branch until it outweighs ours.
+    //Either way the seal has to check out as far as it can without the parent.
+    fn hold_orphan(&mut self, chain: &mut BlockState<P>, block: Block<P>)
+    {
+        let height = block.height;
+
+        if height <= chain.finalized || chain.blocks.get(height as usize).is_some_and(|held| held.hash == block.hash)
+        {
+            return;
+        }
+
+        if let Err(e) = chain.engine().verify_header(&block)
+        {
+            println!("Not holding orphan block at height {height}! {e}");
+            return;
+        }
+
+        if !self.orphans.insert(block)
+        {
+            return;
+        }
+
+        println!("Holding orphan block at height {height}, {} orphans waiting", self.orphans.len());
+
+        if self.reorganize_to_orphans(chain)
+        {
+            self.on_reorg(chain);
+        }
+    }
+
+    //Validators prevote the new tip, which may complete a quorum on its own.
+    fn vote_finality(&mut self, chain: &mut BlockState<P>)
+    {
+        let Some(finality) = &self.finality else { return };
+
+        let tip = chain.blocks.last().unwrap();
+        let votes = finality.vote(chain, tip.height, &tip.hash);
+
+        self.publish_votes(votes);
+        self.apply_finality(chain);
+    }
+
+    fn publish_votes(&mut self, votes: Vec<FinalityVote>)
+    {
+        for vote in votes
+        {
+            _ = self.publish(self.finality_topic.hash(), &vote);
+        }
+    }
+
+    fn apply_finality(&mut self, chain: &mut BlockState<P>)
+    {
+        let Some(height) = self.finality.as_ref().and_then(|finality| finality.apply(chain)) else { return };
+
+        let hash = chain.blocks[height as usize].hash.clone();
+        println!("Finalized height {height}");
+
+        self.save(chain);
+        _ = self.events.send(NodeEvent::Finalized { height, hash });
+    }
+
+    fn publish<M: Serialize>(&mut self, topic: TopicHash, message: &M) -> Result<(), Error>
+    {
+        let serialized = wire::encode_message(self.wire, message)?;
+        self.stats.record_gossip_sent(self.wire, serialized.len(), message);
+
+        self.swarm.behaviour_mut().gossipsub.publish(topic, serialized).map_err(|e| Error::RequestFailed(e.to_string()))?;
+        Ok(())
+    }
+
+    //Blocks produced locally, by our miner or submitted through the handle, are added and then gossiped as compact blocks.
+    async fn publish_block(&mut self, block: Block<P>) -> Result<(), Error>
+    {
+        let compact = CompactBlock::new(&block);
+
+        let chain = self.chain.clone();
+        let mut chain_lock = chain.write().await;
+
+        if let Err(e) = chain_lock.add_block(block.clone())
+        {
+            //Someone else's block took the height while we were sealing ours.
+            if chain_lock.is_stale(&block)
+            {
+                self.stats.record_orphans(1);
+            }
+
+            return Err(e);
+        }
+
+        println!("Block found! Adding...");
+        _ = self.publish(self.topic.hash(), &compact);
+
+        self.on_new_tip(&mut chain_lock);
+        Ok(())
+    }
+
+    fn send_request(&mut self, peer: &PeerId, request: BlockRequest) -> OutboundRequestId
+    {
+        let request_id = self.swarm.behaviour_mut().request_response.send_request(peer, request.clone());
+        self.outbound.insert(request_id, request);
+
+        request_id
+    }
+
+    //Full nodes, the peers subscribed to blocks, are asked first. Light clients only hold headers, but one may still be
+    //all we have. Once every peer has been asked the request fails with the last error.
+    fn request_proof(&mut self, mut request: ProofRequest)
+    {
+        let blocks = self.topic.hash();
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+
+        let full_nodes = gossipsub.all_peers().filter(|(_, topics)| topics.contains(&&blocks)).map(|(peer, _)| *peer);
+        let next = full_nodes.chain(self.peers.keys().copied()).find(|peer| !request.asked.contains(peer));
+
+        let Some(peer) = next else
+        {
+            _ = request.reply.send(Err(request.error));
+            return;
+        };
+
+        request.asked.push(peer);
+
+        let request_id = self.send_request(&peer, BlockRequest::GetProof(request.id.clone()));
+        self.proofs.insert(request_id, request);
+    }
+
+    fn request_block(&mut self, peer: &PeerId, height: u64)
+    {
+        self.send_request(peer, BlockRequest::GetBlock(height));
+    }
+
+    fn message_limit(&self, topic: &TopicHash) -> usize
+    {
+        if *topic == self.topic.hash() { MAX_BLOCK_SIZE } else { MAX_SMALL_MESSAGE_SIZE }
+    }
+
+    fn penalize(&mut self, peer: PeerId, points: u32, reason: &str)
+    {
+        let now = Instant::now();
+        self.misbehaviour.retain(|_, (score, updated)| decayed(*score, *updated, now) > 0);
+
+        let (score, updated) = self.misbehaviour.entry(peer).or_insert((0, now));
+        *score = decayed(*score, *updated, now) + points;
+        *updated = now;
+
+        println!("Penalized {peer} for {reason}, score {score}");
+
+        if *score >= BAN_SCORE && !self.is_banned(&peer)
+        {
+            println!("Banning {peer} for {} minutes", BAN_DURATION.as_secs() / 60);
+            self.banned.insert(peer, now + BAN_DURATION);
+            self.misbehaviour.remove(&peer);
+            _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    fn is_banned(&mut self, peer: &PeerId) -> bool
+    {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+
+        self.banned.contains_key(peer)
+    }
+
+    //Only accepted messages are forwarded to our other peers, ignored and rejected ones are dropped.
+    fn validate(&mut self, message_id: &MessageId, source: &PeerId, acceptance: MessageAcceptance)
+    {
+        _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(message_id, source, acceptance);
+    }
+
+    fn request_headers(&mut self, peer: &PeerId, from: u64)
+    {
+        self.send_request(peer, BlockRequest::GetHeaders { from, limit: MAX_HEADERS });
+    }
+
+    async fn handle_swarm_event(&mut self, event: SwarmEvent<MainEvent<P>>)
+    {
+        if let SwarmEvent::Behaviour(MainEvent::Gossipsub(gossipsub::Event::Message{ message, .. })) = &event
+        {
+            self.stats.record_gossip_received(message.data.len());
+        }
+
+        match event
+        {
+            //Checked on the raw bytes, an oversized message is never deserialized.
+            SwarmEvent::Behaviour(MainEvent::Gossipsub(gossipsub::Event::Message{ propagation_source, message_id, message })) if message.data.len() > self.message_limit(&message.topic) =>
+            {
+                self.validate(&message_id, &propagation_source, MessageAcceptance::Reject);
+                self.penalize(propagation_source, OVERSIZED_PENALTY, "an oversized gossip message");
+            },
+            SwarmEvent::Behaviour(MainEvent::Gossipsub(gossipsub::Event::Message{ propagation_source, message_id, message })) if message.topic == self.finality_topic.hash() =>
+            {
+                let Ok(vote) = wire::decode_message::<FinalityVote>(&message.data) else
+                {
+                    self.validate(&message_id, &propagation_source, MessageAcceptance::Reject);
+                    return;
+                };
+
+                let chain = self.chain.clone();
+                let mut chain_lock = chain.write().await;
+
+                //Without a gadget of our own we can't tell a committee vote from a forged one, so we don't vouch for any.
+                let acceptance = match self.finality.as_ref().map(|finality| finality.check_vote(&chain_lock, &vote))
+                {
+                    Some(Ok(_)) => MessageAcceptance::Accept,
+                    None | Some(Err(Error::FinalizedBlock | Error::OutOfBounds)) => MessageAcceptance::Ignore,
+                    Some(Err(e)) =>
+                    {
+                        println!("Rejected finality vote! {e}");
+                        MessageAcceptance::Reject
+                    },
+                };
+
+                let accepted = matches!(acceptance, MessageAcceptance::Accept);
+                self.validate(&message_id, &propagation_source, acceptance);
+
+                let Some(finality) = self.finality.as_ref().filter(|_| accepted) else { return };
+
+                let votes = finality.add_vote(&chain_lock, &vote);
+
+                self.publish_votes(votes);
+                self.apply_finality(&mut chain_lock);
+            },
+            SwarmEvent::Behaviour(MainEvent::Gossipsub(gossipsub::Event::Message{ propagation_source, message_id, message })) if message.topic == self.transaction_topic.hash() =>
+            {
+                let chain = self.chain.clone();
+                let chain_lock = chain.read().await;
+
+                let acceptance = match wire::decode_message::<Transaction>(&message.data)
+                {
+                    Ok(transaction) => match self.add_to_mempool(&chain_lock, transaction.clone())
+                    {
+                        Ok(true) =>
+                        {
+                            println!("Transaction added to the mempool");
+                            _ = self.events.send(NodeEvent::NewTransaction(transaction));
+                            MessageAcceptance::Accept
+                        },
+                        Ok(false) | Err(Error::MempoolFull) => MessageAcceptance::Ignore,
+                        Err(e) =>
+                        {
+                            println!("Rejected transaction! {e}");
+                            MessageAcceptance::Reject
+                        },
+                    },
+                    Err(_) =>
+                    {
+                        println!("Data lost in transmission...");
+                        MessageAcceptance::Reject
+                    },
+                };
+
+                self.validate(&message_id, &propagation_source, acceptance);
+            },
+            SwarmEvent::Behaviour(MainEvent::Gossipsub(gossipsub::Event::Message{ propagation_source, message_id, message })) =>
+            {
+                match wire::decode_message::<CompactBlock<P>>(&message.data)
+                {
+                    Ok(compact) =>
+                    {
+                        let acceptance = self.check_compact_block(&compact).await;
+                        let rejected = matches!(acceptance, MessageAcceptance::Reject);
+                        self.validate(&message_id, &propagation_source, acceptance);
+
+                        if !rejected
+                        {
+                            self.handle_compact_block(compact, propagation_source).await;
+                        }
+                    },
+                    Err(_) =>
+                    {
+                        println!("Data lost in transmission...");
+                        self.validate(&message_id, &propagation_source, MessageAcceptance::Reject);
+                    },
+                }
+            },
+            SwarmEvent::Behaviour(MainEvent::Ping(ping_event)) =>
+            {
+                println!("Pinging! {:?}", ping_event);
+            },
+            SwarmEvent::Behaviour(MainEvent::Mdns(mdns_event)) =>
+            {
+                //For future reference, this event contains a vector of (peer_id, multiaddr)
+                match mdns_event
+                {
+                    mdns::Event::Discovered(list) =>
+                    {
+                        println!("Discovering...");
+                        let mut discovered: HashMap<_, Vec<_>> = HashMap::new();
+
+                        for (peer_id, addr) in list
+                        {
+                            discovered.entry(peer_id).or_default().push(addr);
+                        }
+
+                        for (peer_id, addrs) in discovered
+                        {
+                            if self.is_banned(&peer_id)
+                            {
+                                continue;
+                            }
+
+                            println!("mDNS discovered a new peer! {peer_id}");
+
+                            self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+
+                            if let Err(e) = self.swarm.dial(dial_opts(peer_id, addrs))
+                            {
+                                println!("Failed to dial {peer_id}: {e}");
+                            }
+                        }
+                    }
+                    mdns::Event::Expired(list) =>
+                    {
+                        println!("Expired...");
+                        for (peer_id, _) in list
+                        {
+                            println!("mDNS peer has expired...{peer_id}");
+                            self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        }
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(MainEvent::RequestResponse(request_response::Event::Message { peer, message, .. })) =>
+            {
+                match message
+                {
+                    request_response::Message::Request { request, channel, .. } => self.queue_request(peer, request, channel),
+
+                    request_response::Message::Response { request_id, response } =>
+                    {
+                        let sent = self.outbound.remove(&request_id);
+
+                        match response
+                        {
+                            BlockResponse::FoundBlock(block) => self.handle_synced_block(peer, *block).await,
+                            BlockResponse::BlockNotFound(height) =>
+                            {
+                                println!("Not found at height {height}");
+                            }
+                            BlockResponse::Throttled { retry_after_ms } =>
+                            {
+                                let retry_after = Duration::from_millis(retry_after_ms).min(MAX_RETRY_AFTER);
+
+                                if let Some(mut request) = self.proofs.remove(&request_id)
+                                {
+                                    request.error = Error::RequestFailed(format!("Throttled, retry in {} ms", retry_after.as_millis()));
+                                    self.request_proof(request);
+                                    return;
+                                }
+
+                                let Some(request) = sent else { return };
+
+                                println!("Throttled by {peer}, retrying {} in {} ms", request.kind(), retry_after.as_millis());
+                                self.retries.push((Instant::now() + retry_after, peer, request));
+                            }
+                            BlockResponse::Transactions { hash, transactions } => self.handle_missing_transactions(peer, hash, transactions).await,
+                            BlockResponse::Time(time) =>
+                            {
+                                self.clock.record(peer, time);
+                                self.adjust_clock().await;
+                            }
+                            BlockResponse::Headers(headers) if self.light => self.handle_synced_headers(peer, headers).await,
+                            BlockResponse::Headers(headers) => self.handle_assumed_headers(peer, headers).await,
+                            BlockResponse::Proof(proof) =>
+                            {
+                                let Some(mut request) = self.proofs.remove(&request_id) else { return };
+
+                                let result = match proof
+                                {
+                                    Some(proof) => self.chain.read().await.verify_proof(&request.id, &proof).map(|_| proof),
+                                    None => Err(Error::TransactionNotFound),
+                                };
+
+                                match result
+                                {
+                                    Ok(proof) => _ = request.reply.send(Ok(proof)),
+                                    Err(e) =>
+                                    {
+                                        request.error = e;
+                                        self.request_proof(request);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(MainEvent::RequestResponse(request_response::Event::OutboundFailure { peer, request_id, error, .. })) =>
+            {
+                if let Some(BlockRequest::GetTransactions { hash, .. }) = self.outbound.remove(&request_id)
+                {
+                    self.fetch_full_block(peer, &hash);
+                }
+
+                if let request_response::OutboundFailure::Io(e) = &error
+                    && is_over_size_cap(e)
+                {
+                    self.penalize(peer, OVERSIZED_PENALTY, "an oversized response");
+                }
+
+                if let Some(mut request) = self.proofs.remove(&request_id)
+                {
+                    request.error = Error::RequestFailed(error.to_string());
+                    self.request_proof(request);
+                }
+            }
+            SwarmEvent::Behaviour(MainEvent::RequestResponse(request_response::Event::InboundFailure { peer, error: request_response::InboundFailure::Io(e), .. })) if is_over_size_cap(&e) =>
+            {
+                self.penalize(peer, OVERSIZED_PENALTY, "an oversized request");
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } =>
+            {
+                if self.is_banned(&peer_id)
+                {
+                    _ = self.swarm.disconnect_peer_id(peer_id);
+                    return;
+                }
+
+                self.peers.insert(peer_id, endpoint.get_remote_address().clone());
+
+                if num_established.get() == 1
+                {
+                    _ = self.events.send(NodeEvent::PeerConnected(peer_id.to_string()));
+                    self.send_request(&peer_id, BlockRequest::GetTime);
+
+                    let chain = self.chain.clone();
+                    let chain_lock = chain.read().await;
+
+                    if self.light
+                    {
+                        let next_height = chain_lock.blocks.len() as u64;
+                        self.request_headers(&peer_id, next_height);
+                    }
+                    else if let Some(from) = chain_lock.assumed_headers_from()
+                    {
+                        self.request_headers(&peer_id, from);
+                    }
+                }
+            },
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } =>
+            {
+                self.peers.remove(&peer_id);
+                self.forks.remove(&peer_id);
+                self.limiter.retain(|(peer, _)| *peer != peer_id);
+                self.requests.retain(|(peer, _, _)| *peer != peer_id);
+                self.retries.retain(|(_, peer, _)| *peer != peer_id);
+                self.clock.forget(&peer_id);
+                self.adjust_clock().await;
+                _ = self.events.send(NodeEvent::PeerDisconnected(peer_id.to_string()));
+            },
+            _ => {}
+        }
+    }
+
+    //Over its rate limit, or with the queue full, a peer is told to come back later instead of being served.
+    fn queue_request(&mut self, peer: PeerId, request: BlockRequest, channel: ResponseChannel<BlockResponse<P>>)
+    {
+        let throttle = match self.limiter.check((peer, request.kind()), request.rate_limit())
+        {
+            Err(retry_after) => Some(retry_after),
+            Ok(()) if self.requests.len() >= MAX_QUEUED_REQUESTS => Some(QUEUE_FULL_RETRY),
+            Ok(()) => None,
+        };
+
+        match throttle
+        {
+            Some(retry_after) => self.respond(peer, channel, BlockResponse::throttled(retry_after)),
+            None => self.requests.push_back((peer, request, channel)),
+        }
+    }
+
+    //Answers a batch of queued requests under one read lock.
+    async fn serve_requests(&mut self)
+    {
+        let chain = self.chain.clone();
+        let chain_lock = chain.read().await;
+
+        for _ in 0..MAX_RESPONSES_PER_TICK
+        {
+            let Some((peer, request, channel)) = self.requests.pop_front() else { break };
+
+            let response = self.build_response(&chain_lock, request);
+            self.respond(peer, channel, response);
+        }
+    }
+
+    fn build_response(&self, chain: &BlockState<P>, request: BlockRequest) -> BlockResponse<P>
+    {
+        match request
+        {
+            //A light client only has headers, handing those out as blocks would fail their merkle_root.
+            BlockRequest::GetBlock(height) => match chain.blocks.get(height as usize).filter(|_| !self.light)
+            {
+                Some(block) => BlockResponse::FoundBlock(Box::new(block.clone())),
+                None => BlockResponse::BlockNotFound(height),
+            },
+            BlockRequest::GetHeaders { from, limit } =>
+            {
+                //Stops early rather than send a batch the other side would refuse to read.
+                let mut size = 0usize;
+                let headers = chain.blocks.iter()
+                    .skip(from as usize)
+                    .take(limit.min(MAX_HEADERS) as usize)
+                    .map(Block::header)
+                    .take_while(|header|
+                    {
+                        size = size.saturating_add(serde_json::to_vec(header).map_or(usize::MAX, |bytes| bytes.len() + 1));
+                        size < MAX_RESPONSE_SIZE as usize
+                    })
+                    .collect();
+
+                BlockResponse::Headers(headers)
+            }
+            BlockRequest::GetTransactions { hash, indexes } =>
+            {
+                let transactions = chain.find_block(&hash)
+                    .map(|block| indexes.iter().filter_map(|index| block.transactions.get(*index).cloned()).collect())
+                    .unwrap_or_default();
+
+                BlockResponse::Transactions { hash, transactions }
+            }
+            BlockRequest::GetProof(id) => BlockResponse::Proof(chain.transaction_proof(&id)),
+            BlockRequest::GetTime => BlockResponse::Time(Utc::now().timestamp()),
+        }
+    }
+
+    //The channel is gone once the peer disconnects or gives up on the request, nothing left to do then.
+    fn respond(&mut self, peer: PeerId, channel: ResponseChannel<BlockResponse<P>>, response: BlockResponse<P>)
+    {
+        if self.swarm.behaviour_mut().request_response.send_response(channel, response).is_err()
+        {
+            println!("Could not answer {peer}, the request was dropped");
+        }
+    }
+
+    fn send_retries(&mut self)
+    {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.retries).into_iter().partition(|(at, _, _)| *at <= now);
+        self.retries = waiting;
+
+        for (_, peer, request) in due
+        {
+            self.send_request(&peer, request);
+        }
+    }
+
+    //Rebuilds the block from our mempool and asks the sender for whatever we don't have.
+    //A block is only forwarded once its seal checks out against a parent we hold. One we already have, or can't place
+    //yet, is kept to ourselves without blaming whoever sent it.
+    async fn check_compact_block(&mut self, compact: &CompactBlock<P>) -> MessageAcceptance
+    {
+        let chain = self.chain.clone();
+        let chain_lock = chain.read().await;
+
+        if self.partials.contains_key(&compact.header.hash) || chain_lock.find_block(&compact.header.hash).is_some()
+        {
+            return MessageAcceptance::Ignore;
+        }
+
+        match chain_lock.check_header(&compact.header, compact.short_ids.len())
+        {
+            Ok(()) => MessageAcceptance::Accept,
+            Err(Error::OutOfBounds | Error::TimestampTooFarAhead) => MessageAcceptance::Ignore,
+            Err(e) =>
+            {
+                println!("Rejected gossiped block {}! {e}", compact.header.hash);
+                MessageAcceptance::Reject
+            },
+        }
+    }
+
+    //Missing transactions are asked of the peer that relayed the block, we may not be connected to its author.
+    async fn handle_compact_block(&mut self, compact: CompactBlock<P>, peer: PeerId)
+    {
+        let hash = compact.header.hash.clone();
+
+        if self.partials.contains_key(&hash) || self.chain.read().await.find_block(&hash).is_some()
+        {
+            return;
+        }
+
+        let height = compact.header.height;
+        let partial = compact.prefill(&self.mempool.snapshot());
+        let missing = partial.missing();
+
+        if missing.is_empty()
+        {
+            match partial.into_block()
+            {
+                Some(block) => self.handle_gossiped_block(block, Some(peer)).await,
+                None =>
+                {
+                    println!("Could not reconstruct block {hash}, requesting it in full");
+                    self.request_block(&peer, height);
+                }
+            }
+
+            return;
+        }
+
+        println!("Reconstructing block {hash}, fetching {} missing transactions", missing.len());
+
+        if self.partials.len() >= MAX_PARTIAL_BLOCKS
+        {
+            self.partials.clear();
+        }
+
+        self.partials.insert(hash.clone(), partial);
+        self.send_request(&peer, BlockRequest::GetTransactions { hash, indexes: missing });
+    }
+
+    //Gives up rebuilding a block whose missing transactions couldn't be fetched and asks for all of it, from another peer if
+    //the one we asked is gone.
+    fn fetch_full_block(&mut self, peer: PeerId, hash: &str)
+    {
+        let Some(partial) = self.partials.remove(hash) else { return };
+
+        let peer = if self.swarm.is_connected(&peer) { Some(peer) } else { self.peers.keys().next().copied() };
+
+        if let Some(peer) = peer
+        {
+            println!("Could not fetch the missing transactions of block {hash}, requesting it in full");
+            self.request_block(&peer, partial.compact.header.height);
+        }
+    }
+
+    async fn handle_missing_transactions(&mut self, peer: PeerId, hash: String, transactions: Vec<Transaction>)
+    {
+        let Some(mut partial) = self.partials.remove(&hash) else { return };
+        let height = partial.compact.header.height;
+
+        partial.fill(transactions);
+
+        match partial.into_block()
+        {
+            Some(block) => self.handle_gossiped_block(block, Some(peer)).await,
+            None =>
+            {
+                println!("Could not reconstruct block {hash}, requesting it in full");
+                self.request_block(&peer, height);
+            }
+        }
+    }
+
+    async fn handle_gossiped_block(&mut self, block: Block<P>, source: Option<PeerId>)
+    {
+        let chain = self.chain.clone();
+        let mut chain_lock = chain.write().await;
+
+        match chain_lock.add_block(block.clone())
+        {
+            Ok(()) => self.on_new_tip(&mut chain_lock),
+            Err(Error::TimestampTooFarAhead) => self.hold_future(block, source),
+            Err(e) =>
+            {
+                println!("An error has occured! {e}");
+
+                if chain_lock.is_stale(&block)
+                {
+                    self.stats.record_orphans(1);
+                }
+                self.hold_orphan(&mut chain_lock, block);
+
+                if let Some(sender_peer_id) = source
+                {
+                    let missing_height = chain_lock.blocks.len() as u64;
+                    self.request_block(&sender_peer_id, missing_height);
+                }
+            }
+        };
+    }
+
+    async fn handle_synced_block(&mut self, peer: PeerId, block: Block<P>)
+    {
+        println!("Received response, Adding block!");
+
+        let chain = self.chain.clone();
+        let mut chain_lock = chain.write().await;
+        let height = block.height as usize;
+
+        match chain_lock.add_block(block.clone())
+        {
+            Ok(()) =>
+            {
+                self.forks.remove(&peer);
+                self.on_new_tip(&mut chain_lock);
+
+                let next_height = chain_lock.blocks.len() as u64;
+                self.request_block(&peer, next_height);
+            },
+            Err(_) if chain_lock.blocks.get(height).is_some_and(|known| known.hash == block.hash) => {},
+            Err(Error::TimestampTooFarAhead) => self.hold_future(block, Some(peer)),
+            //The peer is on another branch, walk back until its blocks connect to ours.
+            Err(e) if height >= 1 && height <= chain_lock.blocks.len() =>
+            {
+                let mut branch = self.forks.remove(&peer).unwrap_or_default();
+
+                //Only keep extending the walk if this block is the parent we asked for.
+                if branch.last().is_some_and(|child| child.previous_hash != block.hash)
+                {
+                    branch.clear();
+                }
+
+                branch.push(block);
+
+                if chain_lock.blocks[height - 1].hash == branch.last().unwrap().previous_hash
+                {
+                    branch.reverse();
+                    let added = branch.clone();
+
+                    match chain_lock.reorganize(branch)
+                    {
+                        Ok(removed) =>
+                        {
+                            println!("Reorganized! {} blocks replaced from height {height}", removed.len());
+                            self.record_reorg(&chain_lock, removed, added);
+                            self.on_reorg(&mut chain_lock);
+
+                            let next_height = chain_lock.blocks.len() as u64;
+                            self.request_block(&peer, next_height);
+                        },
+                        Err(Error::TimestampTooFarAhead) => self.hold_branch(&chain_lock, added, Some(peer)),
+                        Err(e) => println!("Reorganization failed! {e}"),
+                    }
+                }
+                else if branch.len() < MAX_FORK_DEPTH
+                {
+                    println!("{e}, walking back to height {}", height - 1);
+                    self.forks.insert(peer, branch);
+                    self.request_block(&peer, height as u64 - 1);
+                }
+                else
+                {
+                    println!("Fork from {peer} is deeper than {MAX_FORK_DEPTH} blocks, giving up");
+                }
+            },
+            Err(e) =>
+            {
+                println!("An error has occured! {e}");
+                self.hold_orphan(&mut chain_lock, block);
+            },
+        };
+    }
+
+    //Headers come in batches starting at the height we asked for. The ones we already hold are skipped, the rest
+    //either extend our tip or replace a heavier branch, and we keep asking until a peer has nothing new.
+    async fn handle_synced_headers(&mut self, peer: PeerId, headers: Vec<BlockHeader<P>>)
+    {
+        let Some(first_height) = headers.first().map(|header| header.height) else { return };
+
+        let chain = self.chain.clone();
+        let mut chain_lock = chain.write().await;
+
+        let known = headers.iter().take_while(|header| chain_lock.blocks.get(header.height as usize).is_some_and(|block| block.hash == header.hash)).count();
+        let branch: Vec<Block<P>> = headers.into_iter().skip(known).map(BlockHeader::into_block).collect();
+
+        let Some(first) = branch.first() else
+        {
+            let next_height = chain_lock.blocks.len() as u64;
+            self.request_headers(&peer, next_height);
+            return;
+        };
+
+        let height = first.height as usize;
+
+        if height == chain_lock.blocks.len()
+        {
+            let mut added = false;
+            let mut headers = branch.into_iter();
+
+            while let Some(block) = headers.next()
+            {
+                match chain_lock.add_block(block.clone())
+                {
+                    Ok(()) => added = true,
+                    Err(Error::TimestampTooFarAhead) =>
+                    {
+                        self.hold_branch(&chain_lock, std::iter::once(block).chain(headers).collect(), Some(peer));
+                        break;
+                    },
+                    Err(e) =>
+                    {
+                        println!("Rejected header! {e}");
+                        break;
+                    },
+                }
+            }
+
+            if !added
+            {
+                return;
+            }
+
+            println!("Synced headers up to height {}", chain_lock.blocks.len() - 1);
+            self.on_new_tip(&mut chain_lock);
+        }
+        else if height >= 1 && height < chain_lock.blocks.len() && chain_lock.blocks[height - 1].hash == first.previous_hash
+        {
+            let added = branch.clone();
+
+            match chain_lock.reorganize(branch)
+            {
+                Ok(removed) =>
+                {
+                    println!("Reorganized! {} headers replaced from height {height}", removed.len());
+                    self.stats.record_orphans(removed.len());
+                    self.save(&chain_lock);
+                    _ = self.events.send(NodeEvent::Reorg { removed, added });
+                },
+                Err(Error::TimestampTooFarAhead) =>
+                {
+                    self.hold_branch(&chain_lock, added, Some(peer));
+                    return;
+                },
+                Err(e) =>
+                {
+                    println!("Reorganization failed! {e}");
+                    return;
+                }
+            }
+        }
+        //Our tip is not on the peer's branch, ask again from further back so the batch includes the fork point.
+        else if first_height as usize >= chain_lock.blocks.len() && first_height > 1
+        {
+            let from = first_height.saturating_sub(MAX_FORK_DEPTH as u64).max(1);
+            println!("Headers from {peer} don't connect, asking again from height {from}");
+
+            self.request_headers(&peer, from);
+            return;
+        }
+        else
+        {
+            println!("Headers from {peer} fork off more than {MAX_FORK_DEPTH} blocks back, giving up");
+            return;
+        }
+
+        let next_height = chain_lock.blocks.len() as u64;
+        self.request_headers(&peer, next_height);
+    }
+
+    //A full node with --assume-valid fetches the headers leading to the assumed valid block first, the blocks on them
+    //skip the expensive checks as they arrive.
+    async fn handle_assumed_headers(&mut self, peer: PeerId, headers: Vec<BlockHeader<P>>)
+    {
+        if headers.is_empty()
+        {
+            return;
+        }
+
+        let chain = self.chain.clone();
+        let mut chain_lock = chain.write().await;
+
+        if chain_lock.assumed_headers_from().is_none()
+        {
+            return;
+        }
+
+        match chain_lock.add_assumed_headers(headers)
+        {
+            Ok(true) => println!("Headers up to the assumed valid block check out, skipping checks below it"),
+            Ok(false) =>
+            {
+                if let Some(from) = chain_lock.assumed_headers_from()
+                {
+                    self.request_headers(&peer, from);
+                }
+            },
+            Err(e) => println!("Rejected headers from {peer}! {e}"),
+        }
+    }
+
+    //Returns false once the node should shut down.
+    async fn handle_command(&mut self, command: NodeCommand<P>) -> bool
+    {
+        match command
+        {
+            NodeCommand::GetPeerInfo(reply) =>
+            {
+                let info = self.peers.iter().map(|(peer_id, address)| PeerInfo
+                {
+                    peer_id: peer_id.to_string(),
+                    address: address.to_string(),
+                }).collect();
+
+                _ = reply.send(info);
+            }
+            NodeCommand::SubmitTransaction(transaction, reply) =>
+            {
+                let chain = self.chain.clone();
+                let chain_lock = chain.read().await;
+                let added = self.add_to_mempool(&chain_lock, transaction.clone());
+                drop(chain_lock);
+
+                if let Ok(true) = added
+                {
+                    _ = self.publish(self.transaction_topic.hash(), &transaction);
+
+                    _ = self.events.send(NodeEvent::NewTransaction(transaction));
+                }
+
+                _ = reply.send(added);
+            }
+            NodeCommand::SubmitBlock(block, reply) =>
+            {
+                _ = reply.send(self.publish_block(block).await);
+            }
+            NodeCommand::VerifyTransaction(id, reply) =>
+            {
+                if !self.light
+                {
+                    _ = reply.send(self.chain.read().await.transaction_proof(&id).ok_or(Error::TransactionNotFound));
+                    return true;
+                }
+
+                self.request_proof(ProofRequest { id, reply, asked: Vec::new(), error: Error::RequestFailed(String::from("No peers to ask for a proof")) });
+            }
+            NodeCommand::StartMining(reply) =>
+            {
+                let was_mining = self.miner.enabled;
+
+                if !was_mining
+                {
+                    let chain_lock = self.chain.read().await;
+                    self.miner.start(chain_lock.blocks.last().unwrap());
+                }
+
+                _ = reply.send(was_mining);
+            }
+            NodeCommand::StopMining(reply) =>
+            {
+                let was_mining = self.miner.enabled;
+                self.miner.stop();
+
+                _ = reply.send(was_mining);
+            }
+            NodeCommand::Shutdown =>
+            {
+                println!("Shutting down...");
+                self.miner.stop();
+                return false;
+            }
+        }
+
+        true
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+use crate::block::Block;
+use crate::payload::Payload;
+
+pub const MAX_ORPHANS: usize = 64;
+pub const MAX_ORPHAN_AGE: Duration = Duration::from_secs(600);
+
+//Blocks that arrived before their parent, or that sit on a branch competing with ours, indexed by their parent.
+#[derive(Debug)]
+pub struct OrphanPool<P = String>
+{
+    blocks: HashMap<String, (Block<P>, Instant)>,
+    children: HashMap<String, Vec<String>>,
+}
+
+impl<P: Payload> Default for OrphanPool<P>
+{
+    fn default() -> Self
+    {
+        Self { blocks: HashMap::new(), children: HashMap::new() }
+    }
+}
+
+impl<P: Payload> OrphanPool<P>
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool
+    {
+        self.blocks.contains_key(hash)
+    }
+
+    //Expired orphans go first, then the oldest one if the pool is still full. Returns false for a block we already hold.
+    pub fn insert(&mut self, block: Block<P>) -> bool
+    {
+        if self.contains(&block.hash)
+        {
+            return false;
+        }
+
+        self.expire();
+
+        if self.blocks.len() >= MAX_ORPHANS
+            && let Some(oldest) = self.blocks.iter().min_by_key(|(_, (_, received))| *received).map(|(hash, _)| hash.clone())
+        {
+            self.remove(&oldest);
+        }
+
+        self.children.entry(block.previous_hash.clone()).or_default().push(block.hash.clone());
+        self.blocks.insert(block.hash.clone(), (block, Instant::now()));
+
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Block<P>>
+    {
+        self.blocks.values().map(|(block, _)| block)
+    }
+
+    //The orphans waiting on parent. They stay in the pool until they are removed, so the ones that lose out to a
+    //sibling are still there if their branch grows heavier.
+    pub fn children(&self, parent: &str) -> Vec<Block<P>>
+    {
+        self.children.get(parent).into_iter().flatten()
+            .filter_map(|hash| self.blocks.get(hash).map(|(block, _)| block.clone()))
+            .collect()
+    }
+
+    //The held block with this hash followed by its longest line of held descendants.
+    pub fn longest_branch(&self, hash: &str) -> Vec<Block<P>>
+    {
+        let Some((block, _)) = self.blocks.get(hash) else { return Vec::new() };
+
+        let mut branch = vec![block.clone()];
+
+        let longest = self.children.get(hash).into_iter().flatten()
+            .map(|child| self.longest_branch(child))
+            .max_by_key(Vec::len)
+            .unwrap_or_default();
+
+        branch.extend(longest);
+        branch
+    }
+
+    pub fn expire(&mut self)
+    {
+        let expired: Vec<String> = self.blocks.iter()
+            .filter(|(_, (_, received))| received.elapsed() > MAX_ORPHAN_AGE)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+
+        for hash in expired
+        {
+            self.remove(&hash);
+        }
+    }
+
+    pub fn remove(&mut self, hash: &str)
+    {
+        let Some((block, _)) = self.blocks.remove(hash) else { return };
+
+        if let Some(siblings) = self.children.get_mut(&block.previous_hash)
+        {
+            siblings.retain(|sibling| sibling != hash);
+
+            if siblings.is_empty()
+            {
+                self.children.remove(&block.previous_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::{ BlockCandidate, BlockState };
+
+    fn genesis() -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        chain.blocks.remove(0)
+    }
+
+    fn child(parent: &Block) -> Block
+    {
+        let candidate = BlockCandidate::new(parent, String::from("orphan"), Vec::new());
+        let hash = candidate.hash(0);
+
+        candidate.into_block(hash, 0)
+    }
+
+    fn backdate(pool: &mut OrphanPool, hash: &str, age: Duration)
+    {
+        pool.blocks.get_mut(hash).unwrap().1 = Instant::now() - age;
+    }
+
+    #[test]
+    fn holds_each_block_once()
+    {
+        let mut pool = OrphanPool::new();
+        let block = child(&genesis());
+
+        assert!(pool.insert(block.clone()));
+        assert!(!pool.insert(block.clone()));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.children(&block.previous_hash).len(), 1);
+    }
+
+    #[test]
+    fn expired_orphans_are_dropped()
+    {
+        let mut pool = OrphanPool::new();
+        let genesis = genesis();
+        let old = child(&genesis);
+        let fresh = child(&genesis);
+
+        pool.insert(old.clone());
+        pool.insert(fresh.clone());
+        backdate(&mut pool, &old.hash, MAX_ORPHAN_AGE + Duration::from_secs(1));
+
+        pool.expire();
+
+        assert!(!pool.contains(&old.hash));
+        assert!(pool.contains(&fresh.hash));
+        assert_eq!(pool.children(&genesis.hash).len(), 1);
+    }
+
+    #[test]
+    fn full_pool_evicts_the_oldest()
+    {
+        let mut pool = OrphanPool::new();
+        let genesis = genesis();
+        let blocks: Vec<Block> = (0..MAX_ORPHANS).map(|_| child(&genesis)).collect();
+
+        for block in &blocks
+        {
+            pool.insert(block.clone());
+        }
+
+        backdate(&mut pool, &blocks[10].hash, Duration::from_secs(5));
+
+        let newcomer = child(&genesis);
+        assert!(pool.insert(newcomer.clone()));
+
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&blocks[10].hash));
+        assert!(pool.contains(&newcomer.hash));
+    }
+
+    #[test]
+    fn siblings_stay_until_removed()
+    {
+        let mut pool = OrphanPool::new();
+        let genesis = genesis();
+        let short = child(&genesis);
+        let long = child(&genesis);
+        let tip = child(&long);
+
+        pool.insert(short.clone());
+        pool.insert(long.clone());
+        pool.insert(tip.clone());
+
+        let branch: Vec<String> = pool.longest_branch(&long.hash).into_iter().map(|block| block.hash).collect();
+        assert_eq!(branch, vec![long.hash.clone(), tip.hash.clone()]);
+
+        pool.remove(&long.hash);
+
+        assert_eq!(pool.children(&genesis.hash).len(), 1);
+        assert!(pool.contains(&short.hash));
+        assert!(pool.contains(&tip.hash));
+    }
+}
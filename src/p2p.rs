@@ -1,41 +1,238 @@
-use libp2p::{gossipsub, mdns, ping,request_response,swarm::NetworkBehaviour};
+use async_trait::async_trait;
+use libp2p::{gossipsub, mdns, ping,request_response,swarm::NetworkBehaviour, StreamProtocol};
+use libp2p::futures::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::block::Block;
+use crate::block::{ Block, BlockHeader, MAX_BLOCK_SIZE };
+use crate::merkle::TransactionProof;
+use crate::payload::Payload;
+use crate::ratelimit::RateLimit;
+use crate::stats::Stats;
+use crate::wire::Encoding;
+use crate::transaction::Transaction;
+
+//Both encodings are offered, the dialer lists its preferred one first and the listener takes the first it knows.
+pub const SYNC_PROTOCOL_CBOR: &str = "/blockchain-sync/cbor/v1";
+pub const SYNC_PROTOCOL_JSON: &str = "/blockchain-sync/v1";
+
+//Upper bound on the headers sent in one response.
+pub const MAX_HEADERS: u64 = 500;
+
+//Transport caps. Gossipsub drops anything bigger before it reaches us, and /blockchain-sync refuses a payload over
+//its cap without decoding it.
+pub const MAX_GOSSIP_SIZE: usize = MAX_BLOCK_SIZE + 64 * 1024;
+pub const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+pub const MAX_RESPONSE_SIZE: u64 = 2 * MAX_BLOCK_SIZE as u64;
+
+//Transactions and finality votes are small, anything past this on their topics is junk.
+pub const MAX_SMALL_MESSAGE_SIZE: usize = 4 * 1024;
+
+//What a payload over its size cap is refused with, so it can be told apart from one that is malformed or cut short.
+#[derive(Debug)]
+pub struct OverSizeCap;
+
+impl fmt::Display for OverSizeCap
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "payload over the size cap")
+    }
+}
+
+impl std::error::Error for OverSizeCap {}
+
+pub fn is_over_size_cap(error: &io::Error) -> bool
+{
+    error.kind() == io::ErrorKind::InvalidData && error.get_ref().is_some_and(|inner| inner.is::<OverSizeCap>())
+}
 
 #[derive(NetworkBehaviour)]
-#[behaviour(to_swarm = "Event")]
-pub struct AppBehaviour
+#[behaviour(to_swarm = "Event<P>")]
+pub struct AppBehaviour<P: Payload>
 {
     pub gossipsub: gossipsub::Behaviour,
     pub ping: ping::Behaviour,
     pub mdns: mdns::tokio::Behaviour,
-    pub request_response: request_response::json::Behaviour<BlockRequest, BlockResponse>,
+    pub request_response: request_response::Behaviour<SyncCodec<P>>,
 }
 
 #[derive(Debug)]
-pub enum Event
+pub enum Event<P = String>
 {
     Gossipsub(gossipsub::Event),
     Ping(ping::Event),
     Mdns(mdns::Event),
-    RequestResponse(request_response::Event<BlockRequest, BlockResponse>),
+    RequestResponse(request_response::Event<BlockRequest, BlockResponse<P>>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum BlockRequest
 {
     GetBlock(u64),
+    GetHeaders { from: u64, limit: u64 },
+    GetProof(String),
+    //The transactions at these positions in the block, for rebuilding a compact block.
+    GetTransactions { hash: String, indexes: Vec<usize> },
+    //The peer's clock, asked once per connection to work out network time.
+    GetTime,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum BlockResponse
+#[serde(bound = "P: Payload")]
+pub enum BlockResponse<P = String>
 {
-    FoundBlock(Block),
+    FoundBlock(Box<Block<P>>),
     BlockNotFound(u64), //404
+    Headers(Vec<BlockHeader<P>>),
+    Proof(Option<TransactionProof>),
+    Transactions { hash: String, transactions: Vec<Transaction> },
+    //The request was not served, send it again after retry_after_ms.
+    Throttled { retry_after_ms: u64 },
+    Time(i64),
+}
+
+impl BlockRequest
+{
+    pub fn kind(&self) -> &'static str
+    {
+        match self
+        {
+            Self::GetBlock(_) => "block",
+            Self::GetHeaders { .. } => "headers",
+            Self::GetProof(_) => "proof",
+            Self::GetTransactions { .. } => "transactions",
+            Self::GetTime => "time",
+        }
+    }
+
+    //Per peer. Block sync asks for one block per round trip, so it gets the most room.
+    pub fn rate_limit(&self) -> RateLimit
+    {
+        match self
+        {
+            Self::GetBlock(_) => RateLimit { burst: 200.0, per_second: 50.0 },
+            Self::GetHeaders { .. } => RateLimit { burst: 20.0, per_second: 5.0 },
+            Self::GetProof(_) => RateLimit { burst: 20.0, per_second: 5.0 },
+            Self::GetTransactions { .. } => RateLimit { burst: 50.0, per_second: 20.0 },
+            Self::GetTime => RateLimit { burst: 5.0, per_second: 1.0 },
+        }
+    }
+}
+
+impl<P> BlockResponse<P>
+{
+    pub fn throttled(retry_after: Duration) -> Self
+    {
+        Self::Throttled { retry_after_ms: retry_after.as_millis() as u64 }
+    }
+}
+
+pub fn sync_protocols(preferred: Encoding) -> Vec<(StreamProtocol, request_response::ProtocolSupport)>
+{
+    let mut protocols = vec![SYNC_PROTOCOL_CBOR, SYNC_PROTOCOL_JSON];
+
+    if preferred == Encoding::Json
+    {
+        protocols.reverse();
+    }
+
+    protocols.into_iter().map(|protocol| (StreamProtocol::new(protocol), request_response::ProtocolSupport::Full)).collect()
+}
+
+//Encodes /blockchain-sync by the negotiated protocol. Payloads over the size caps are refused before they are decoded.
+#[derive(Clone)]
+pub struct SyncCodec<P = String>
+{
+    stats: Arc<Stats>,
+    payload: PhantomData<P>,
+}
+
+impl<P: Payload> SyncCodec<P>
+{
+    pub fn new(stats: Arc<Stats>) -> Self
+    {
+        Self { stats, payload: PhantomData }
+    }
+
+    fn encoding(protocol: &StreamProtocol) -> Encoding
+    {
+        if protocol.as_ref() == SYNC_PROTOCOL_CBOR { Encoding::Cbor } else { Encoding::Json }
+    }
+
+    async fn read<T, M>(&self, protocol: &StreamProtocol, io: &mut T, limit: u64) -> io::Result<M>
+    where
+        T: AsyncRead + Unpin + Send,
+        M: serde::de::DeserializeOwned,
+    {
+        let mut bytes = Vec::new();
+        io.take(limit + 1).read_to_end(&mut bytes).await?;
+
+        if bytes.len() as u64 > limit
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, OverSizeCap));
+        }
+
+        self.stats.record_sync_received(bytes.len());
+
+        Self::encoding(protocol).decode(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    async fn write<T, M>(&self, protocol: &StreamProtocol, io: &mut T, message: &M) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+        M: Serialize + Sync,
+    {
+        let encoding = Self::encoding(protocol);
+        let bytes = encoding.encode(message).map_err(|e| io::Error::other(e.to_string()))?;
+
+        self.stats.record_sync_sent(encoding, bytes.len(), message);
+
+        io.write_all(&bytes).await
+    }
+}
+
+#[async_trait]
+impl<P: Payload> request_response::Codec for SyncCodec<P>
+{
+    type Protocol = StreamProtocol;
+    type Request = BlockRequest;
+    type Response = BlockResponse<P>;
+
+    async fn read_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<BlockRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read(protocol, io, MAX_REQUEST_SIZE).await
+    }
+
+    async fn read_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<BlockResponse<P>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read(protocol, io, MAX_RESPONSE_SIZE).await
+    }
+
+    async fn write_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T, request: BlockRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write(protocol, io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T, response: BlockResponse<P>) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write(protocol, io, &response).await
+    }
 }
 
-impl From<gossipsub::Event> for Event
+impl<P> From<gossipsub::Event> for Event<P>
 {
     fn from(event: gossipsub::Event) -> Self
     {
@@ -43,7 +240,7 @@ impl From<gossipsub::Event> for Event
     }
 }
 
-impl From<ping::Event> for Event
+impl<P> From<ping::Event> for Event<P>
 {
     fn from(event: ping::Event) -> Self
     {
@@ -51,7 +248,7 @@ impl From<ping::Event> for Event
     }
 }
 
-impl From<mdns::Event> for Event
+impl<P> From<mdns::Event> for Event<P>
 {
     fn from(event: mdns::Event) -> Self 
     {
@@ -59,9 +256,9 @@ impl From<mdns::Event> for Event
     }
 }
 
-impl From<request_response::Event<BlockRequest, BlockResponse>> for Event
+impl<P> From<request_response::Event<BlockRequest, BlockResponse<P>>> for Event<P>
 {
-    fn from(event: request_response::Event<BlockRequest, BlockResponse>) -> Self
+    fn from(event: request_response::Event<BlockRequest, BlockResponse<P>>) -> Self
     {
         Self::RequestResponse(event)
     }
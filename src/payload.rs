@@ -0,0 +1,64 @@
+use rand::prelude::*;
+use serde::{ Serialize, de::DeserializeOwned };
+use std::fmt::Debug;
+
+use crate::error::Error;
+
+//Longest String payload a block may carry.
+pub const MAX_DATA_LENGTH: usize = 256;
+
+//Whatever a block carries besides its transactions. The chain only needs to hash, store and check it.
+pub trait Payload: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static
+{
+    //The part of the block hash that covers the payload, it has to change whenever the payload does.
+    fn commitment(&self) -> String;
+
+    //The payload of the very first block.
+    fn genesis() -> Self;
+
+    //The payload this node's own miner puts in the blocks it seals.
+    fn candidate() -> Self;
+
+    //Extra rules a payload can enforce before its block is accepted.
+    fn validate(&self) -> Result<(), Error>
+    {
+        Ok(())
+    }
+}
+
+impl Payload for String
+{
+    fn commitment(&self) -> String
+    {
+        self.clone()
+    }
+
+    fn genesis() -> Self
+    {
+        String::from("DAPProptech is the way")
+    }
+
+    fn candidate() -> Self
+    {
+        let mut rng = rand::rng();
+
+        let mut word = String::with_capacity(20);
+
+        for _ in 0..19
+        {
+            word.push(rng.sample(rand::distr::Alphanumeric) as char );
+        }
+
+        word
+    }
+
+    fn validate(&self) -> Result<(), Error>
+    {
+        if self.len() > MAX_DATA_LENGTH
+        {
+            return Err(Error::BlockTooLarge);
+        }
+
+        Ok(())
+    }
+}
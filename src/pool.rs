@@ -0,0 +1,281 @@
+use serde::{ Serialize, Deserialize };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::block::{ Block, DEFAULT_PREFIX };
+use crate::payload::Payload;
+
+//Nominal reward split between workers each time the pool finds a block.
+pub const BLOCK_REWARD: u64 = 50_000;
+//Worker names are picked by the miners, so only this many are tracked at once.
+pub const MAX_WORKERS: usize = 1024;
+pub const MAX_WORKER_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Share
+{
+    pub worker: String,
+    pub job_id: Uuid,
+    pub difficulty: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Payout
+{
+    pub worker: String,
+    pub shares: u64,
+    pub amount: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PayoutTable
+{
+    pub height: u64,
+    pub hash: String,
+    pub payouts: Vec<Payout>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerShares
+{
+    pub worker: String,
+    pub valid: u64,
+    pub invalid: u64,
+}
+
+#[derive(Debug)]
+pub enum ShareResult<P = String>
+{
+    Share(String),
+    Block(Box<Block<P>>),
+}
+
+#[derive(Debug, Default)]
+struct PoolState
+{
+    log: VecDeque<Share>,
+    workers: HashMap<String, WorkerShares>,
+    seen: HashSet<String>,
+    //Payout tables of blocks the chain hasn't answered for yet, by block hash.
+    pending: HashMap<String, PayoutTable>,
+    rounds: Vec<PayoutTable>,
+}
+
+#[derive(Debug)]
+pub struct Pool
+{
+    pub share_difficulty: usize,
+    window: usize,
+    state: Mutex<PoolState>,
+}
+
+impl Pool
+{
+    pub fn new(share_difficulty: usize, window: usize) -> Self
+    {
+        Self
+        {
+            share_difficulty: share_difficulty.clamp(1, DEFAULT_PREFIX.len()),
+            window: window.max(1),
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    pub fn share_target(&self) -> String
+    {
+        DEFAULT_PREFIX[..self.share_difficulty].to_owned()
+    }
+
+    //Duplicate submissions are only tracked for the current tip, anything older is stale anyway.
+    pub fn new_job(&self)
+    {
+        self.state.lock().unwrap().seen.clear();
+    }
+
+    pub fn submit_share<P: Payload>(&self, worker: &str, job_id: Uuid, block: Block<P>) -> Result<ShareResult<P>, String>
+    {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        if worker.len() > MAX_WORKER_NAME_LENGTH
+        {
+            return Err(format!("Worker names are limited to {MAX_WORKER_NAME_LENGTH} bytes"));
+        }
+
+        //A new worker makes room by dropping those with no share left in the window, they have nothing to be paid for.
+        if !state.workers.contains_key(worker) && state.workers.len() >= MAX_WORKERS
+        {
+            let active: HashSet<&String> = state.log.iter().map(|share| &share.worker).collect();
+            state.workers.retain(|name, _| active.contains(name));
+
+            if state.workers.len() >= MAX_WORKERS
+            {
+                return Err(String::from("Too many workers"));
+            }
+        }
+
+        let entry = state.workers.entry(worker.to_owned()).or_insert_with(|| WorkerShares
+        {
+            worker: worker.to_owned(),
+            valid: 0,
+            invalid: 0,
+        });
+
+        if !block.hash.starts_with(&self.share_target())
+        {
+            entry.invalid += 1;
+            return Err(String::from("Share does not meet the share target"));
+        }
+
+        if !state.seen.insert(block.hash.clone())
+        {
+            entry.invalid += 1;
+            return Err(String::from("Duplicate share"));
+        }
+
+        entry.valid += 1;
+
+        state.log.push_back(Share
+        {
+            worker: worker.to_owned(),
+            job_id,
+            difficulty: self.share_difficulty,
+        });
+
+        while state.log.len() > self.window
+        {
+            state.log.pop_front();
+        }
+
+        if block.hash.starts_with(DEFAULT_PREFIX)
+        {
+            let table = PayoutTable
+            {
+                height: block.height,
+                hash: block.hash.clone(),
+                payouts: pplns(&state.log),
+            };
+
+            //Fixed at the share that found the block, but only paid once the chain accepts it.
+            state.pending.insert(table.hash.clone(), table);
+
+            return Ok(ShareResult::Block(Box::new(block)));
+        }
+
+        Ok(ShareResult::Share(block.hash))
+    }
+
+    pub fn confirm_round(&self, hash: &str)
+    {
+        let mut state = self.state.lock().unwrap();
+        let Some(table) = state.pending.remove(hash) else { return };
+
+        println!("Pool found block {} at height {}", table.hash, table.height);
+
+        for payout in &table.payouts
+        {
+            println!("  {}: {} shares, {} paid", payout.worker, payout.shares, payout.amount);
+        }
+
+        state.rounds.push(table);
+    }
+
+    pub fn discard_round(&self, hash: &str)
+    {
+        self.state.lock().unwrap().pending.remove(hash);
+    }
+
+    pub fn workers(&self) -> Vec<WorkerShares>
+    {
+        self.state.lock().unwrap().workers.values().cloned().collect()
+    }
+
+    pub fn payouts(&self) -> Vec<PayoutTable>
+    {
+        self.state.lock().unwrap().rounds.clone()
+    }
+}
+
+//Pay-per-last-N-shares: the reward is split over the last N shares in the log, weighted by the share difficulty.
+pub fn pplns(log: &VecDeque<Share>) -> Vec<Payout>
+{
+    let mut weights: HashMap<&str, (u64, u64)> = HashMap::new();
+
+    for share in log
+    {
+        let weight = 16u64.pow(share.difficulty as u32);
+        let entry = weights.entry(&share.worker).or_default();
+
+        entry.0 += 1;
+        entry.1 += weight;
+    }
+
+    let total: u64 = weights.values().map(|(_, weight)| weight).sum();
+
+    if total == 0
+    {
+        return Vec::new();
+    }
+
+    let mut payouts: Vec<Payout> = weights.into_iter().map(|(worker, (shares, weight))| Payout
+    {
+        worker: worker.to_owned(),
+        shares,
+        amount: (BLOCK_REWARD as u128 * weight as u128 / total as u128) as u64,
+    }).collect();
+
+    payouts.sort_by(|a, b| b.amount.cmp(&a.amount).then_with(|| a.worker.cmp(&b.worker)));
+    payouts
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::BlockState;
+
+    //submit_share only looks at the hash, so any block will do once its hash meets the share target.
+    fn share(pool: &Pool, nonce: u64) -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        let mut block = chain.blocks.remove(0);
+        block.hash = format!("{}{nonce:x}", pool.share_target());
+        block
+    }
+
+    #[test]
+    fn workers_are_bounded()
+    {
+        let pool = Pool::new(2, MAX_WORKERS);
+
+        for worker in 0..MAX_WORKERS
+        {
+            pool.submit_share(&worker.to_string(), Uuid::new_v4(), share(&pool, worker as u64)).unwrap();
+        }
+
+        assert!(pool.submit_share("late", Uuid::new_v4(), share(&pool, u64::MAX)).is_err());
+        assert!(pool.submit_share(&"x".repeat(MAX_WORKER_NAME_LENGTH + 1), Uuid::new_v4(), share(&pool, 0)).is_err());
+        assert_eq!(pool.workers().len(), MAX_WORKERS);
+    }
+
+    #[test]
+    fn workers_without_shares_in_the_window_make_room()
+    {
+        let pool = Pool::new(2, 1);
+
+        for worker in 0..MAX_WORKERS
+        {
+            pool.submit_share(&worker.to_string(), Uuid::new_v4(), share(&pool, worker as u64)).unwrap();
+        }
+
+        //Only the last worker still has a share in a window of one.
+        pool.submit_share("late", Uuid::new_v4(), share(&pool, u64::MAX)).unwrap();
+
+        let mut workers: Vec<String> = pool.workers().into_iter().map(|shares| shares.worker).collect();
+        workers.sort();
+        assert_eq!(workers, vec![(MAX_WORKERS - 1).to_string(), String::from("late")]);
+    }
+}
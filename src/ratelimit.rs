@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{ Duration, Instant };
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit
+{
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+//Starts full, every request takes a token and tokens come back at a steady rate up to the burst size.
+#[derive(Debug)]
+pub struct TokenBucket
+{
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket
+{
+    pub fn new(limit: RateLimit) -> Self
+    {
+        Self
+        {
+            limit,
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    //Err holds how long until the next token is back.
+    pub fn take(&mut self) -> Result<(), Duration>
+    {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.limit.per_second).min(self.limit.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0
+        {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second))
+    }
+}
+
+//One bucket per key, created the first time the key shows up.
+#[derive(Debug)]
+pub struct RateLimiter<K>
+{
+    buckets: HashMap<K, TokenBucket>,
+}
+
+impl<K: Hash + Eq> Default for RateLimiter<K>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq> RateLimiter<K>
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, key: K, limit: RateLimit) -> Result<(), Duration>
+    {
+        self.buckets.entry(key).or_insert_with(|| TokenBucket::new(limit)).take()
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&K) -> bool)
+    {
+        self.buckets.retain(|key, _| keep(key));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 3.0, per_second: 2.0 };
+
+    #[test]
+    fn starts_full_and_runs_dry()
+    {
+        let mut bucket = TokenBucket::new(LIMIT);
+
+        for _ in 0..3
+        {
+            assert!(bucket.take().is_ok());
+        }
+
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "waited {wait:?}");
+    }
+
+    #[test]
+    fn refills_at_the_rate()
+    {
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.tokens = 0.0;
+
+        //One second at two tokens a second.
+        bucket.last = Instant::now() - Duration::from_secs(1);
+
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst()
+    {
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.tokens = 0.0;
+        bucket.last = Instant::now() - Duration::from_secs(60);
+
+        for _ in 0..3
+        {
+            assert!(bucket.take().is_ok());
+        }
+
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn keys_have_their_own_buckets()
+    {
+        let mut limiter = RateLimiter::new();
+
+        for _ in 0..3
+        {
+            assert!(limiter.check("a", LIMIT).is_ok());
+        }
+
+        assert!(limiter.check("a", LIMIT).is_err());
+        assert!(limiter.check("b", LIMIT).is_ok());
+
+        limiter.retain(|key| *key != "a");
+        assert!(limiter.check("a", LIMIT).is_ok());
+    }
+}
@@ -0,0 +1,185 @@
+use axum::{ Router, Json, routing::get, extract::{ Path, Query, State }, http::StatusCode, response::{ IntoResponse, Response } };
+use serde::{ Serialize, Deserialize };
+use serde_json::json;
+use tokio::net::TcpListener;
+
+use crate::block::Block;
+use crate::node::NodeHandle;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct Pagination
+{
+    from: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl Pagination
+{
+    fn limit(&self) -> usize
+    {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Page<T>
+{
+    from: usize,
+    limit: usize,
+    total: usize,
+    items: Vec<T>,
+}
+
+#[derive(Debug, Serialize)]
+struct TransactionRecord
+{
+    transaction: Transaction,
+    block_hash: Option<String>,
+    height: Option<u64>,
+    confirmed: bool,
+}
+
+fn not_found(message: &str) -> Response
+{
+    (StatusCode::NOT_FOUND, Json(json!({ "error": message }))).into_response()
+}
+
+pub fn serve<P: Payload>(listener: TcpListener, state: NodeHandle<P>)
+{
+    let router = Router::new()
+        .route("/tip", get(tip::<P>))
+        .route("/blocks", get(blocks::<P>))
+        .route("/blocks/{height}", get(block_by_height::<P>))
+        .route("/blocks/hash/{hash}", get(block_by_hash::<P>))
+        .route("/tx/{id}", get(transaction::<P>))
+        .route("/tx/{id}/proof", get(transaction_proof::<P>))
+        .route("/address/{address}/history", get(address_history::<P>))
+        .with_state(state);
+
+    tokio::spawn(async move
+    {
+        if let Err(e) = axum::serve(listener, router).await
+        {
+            println!("REST server stopped! {e}");
+        }
+    });
+}
+
+async fn tip<P: Payload>(State(state): State<NodeHandle<P>>) -> Response
+{
+    let chain = state.chain().await;
+
+    match chain.blocks.last()
+    {
+        Some(block) => Json(block).into_response(),
+        None => not_found("Chain is empty"),
+    }
+}
+
+async fn blocks<P: Payload>(State(state): State<NodeHandle<P>>, Query(pagination): Query<Pagination>) -> Json<Page<Block<P>>>
+{
+    let chain = state.chain().await;
+
+    let from = pagination.from.unwrap_or(0);
+    let limit = pagination.limit();
+
+    Json(Page
+    {
+        from,
+        limit,
+        total: chain.blocks.len(),
+        items: chain.blocks.iter().skip(from).take(limit).cloned().collect(),
+    })
+}
+
+async fn block_by_height<P: Payload>(State(state): State<NodeHandle<P>>, Path(height): Path<u64>) -> Response
+{
+    let chain = state.chain().await;
+
+    match chain.blocks.get(height as usize)
+    {
+        Some(block) => Json(block).into_response(),
+        None => not_found("Block not found"),
+    }
+}
+
+async fn block_by_hash<P: Payload>(State(state): State<NodeHandle<P>>, Path(hash): Path<String>) -> Response
+{
+    let chain = state.chain().await;
+
+    match chain.find_block(&hash)
+    {
+        Some(block) => Json(block).into_response(),
+        None => not_found("Block not found"),
+    }
+}
+
+async fn transaction<P: Payload>(State(state): State<NodeHandle<P>>, Path(id): Path<String>) -> Response
+{
+    let chain = state.chain().await;
+
+    if let Some((block, transaction)) = chain.find_transaction(&id)
+    {
+        return Json(TransactionRecord
+        {
+            transaction: transaction.clone(),
+            block_hash: Some(block.hash.clone()),
+            height: Some(block.height),
+            confirmed: true,
+        }).into_response();
+    }
+
+    match state.mempool().snapshot().into_iter().find(|pending| pending.id == id)
+    {
+        Some(transaction) => Json(TransactionRecord
+        {
+            transaction,
+            block_hash: None,
+            height: None,
+            confirmed: false,
+        }).into_response(),
+        None => not_found("Transaction not found"),
+    }
+}
+
+async fn transaction_proof<P: Payload>(State(state): State<NodeHandle<P>>, Path(id): Path<String>) -> Response
+{
+    match state.transaction_proof(&id).await
+    {
+        Some(proof) => Json(proof).into_response(),
+        None => not_found("Transaction not confirmed"),
+    }
+}
+
+//Newest first, confirmed transactions only.
+async fn address_history<P: Payload>(State(state): State<NodeHandle<P>>, Path(address): Path<String>, Query(pagination): Query<Pagination>) -> Json<Page<TransactionRecord>>
+{
+    let chain = state.chain().await;
+
+    let history: Vec<(&Block<P>, &Transaction)> = chain.blocks.iter().rev()
+        .flat_map(|block| block.transactions.iter().rev().map(move |transaction| (block, transaction)))
+        .filter(|(_, transaction)| transaction.involves(&address))
+        .collect();
+
+    let from = pagination.from.unwrap_or(0);
+    let limit = pagination.limit();
+
+    Json(Page
+    {
+        from,
+        limit,
+        total: history.len(),
+        items: history.into_iter().skip(from).take(limit).map(|(block, transaction)| TransactionRecord
+        {
+            transaction: transaction.clone(),
+            block_hash: Some(block.hash.clone()),
+            height: Some(block.height),
+            confirmed: true,
+        }).collect(),
+    })
+}
@@ -0,0 +1,286 @@
+use axum::{ Router, Json, routing::post, extract::State, body::Bytes, http::StatusCode, response::{ IntoResponse, Response } };
+use serde::{ Serialize, Deserialize };
+use serde_json::{ Value, json };
+use tokio::net::TcpListener;
+
+use crate::authority;
+use crate::consensus::ConsensusRecord;
+use crate::error::Error;
+use crate::merkle::MerkleProof;
+use crate::node::NodeHandle;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest
+{
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError
+{
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse
+{
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl RpcResponse
+{
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self
+    {
+        match outcome
+        {
+            Ok(result) => Self { jsonrpc: "2.0", result: Some(result), error: None, id },
+            Err(error) => Self { jsonrpc: "2.0", result: None, error: Some(error), id },
+        }
+    }
+}
+
+fn rpc_error(code: i64, message: impl Into<String>) -> RpcError
+{
+    RpcError { code, message: message.into() }
+}
+
+fn internal_error(error: Error) -> RpcError
+{
+    rpc_error(INTERNAL_ERROR, error.to_string())
+}
+
+pub fn serve<P: Payload>(listener: TcpListener, state: NodeHandle<P>)
+{
+    let router = Router::new()
+        .route("/", post(handle::<P>))
+        .with_state(state);
+
+    tokio::spawn(async move
+    {
+        if let Err(e) = axum::serve(listener, router).await
+        {
+            println!("RPC server stopped! {e}");
+        }
+    });
+}
+
+async fn handle<P: Payload>(State(state): State<NodeHandle<P>>, body: Bytes) -> Response
+{
+    let request: Value = match serde_json::from_slice(&body)
+    {
+        Ok(value) => value,
+        Err(e) => return Json(RpcResponse::new(Value::Null, Err(rpc_error(PARSE_ERROR, e.to_string())))).into_response(),
+    };
+
+    match request
+    {
+        Value::Array(batch) if batch.is_empty() => Json(RpcResponse::new(Value::Null, Err(rpc_error(INVALID_REQUEST, "Empty batch")))).into_response(),
+        Value::Array(batch) =>
+        {
+            let mut responses = Vec::new();
+
+            for request in batch
+            {
+                if let Some(response) = handle_single(&state, request).await
+                {
+                    responses.push(response);
+                }
+            }
+
+            if responses.is_empty()
+            {
+                return StatusCode::NO_CONTENT.into_response();
+            }
+
+            Json(responses).into_response()
+        }
+        request => match handle_single(&state, request).await
+        {
+            Some(response) => Json(response).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    }
+}
+
+//Notifications (requests without an id) are executed but never answered.
+async fn handle_single<P: Payload>(state: &NodeHandle<P>, request: Value) -> Option<RpcResponse>
+{
+    let request: RpcRequest = match serde_json::from_value(request)
+    {
+        Ok(request) => request,
+        Err(e) => return Some(RpcResponse::new(Value::Null, Err(rpc_error(INVALID_REQUEST, e.to_string())))),
+    };
+
+    if request.jsonrpc != "2.0"
+    {
+        return Some(RpcResponse::new(request.id.unwrap_or(Value::Null), Err(rpc_error(INVALID_REQUEST, "jsonrpc must be \"2.0\""))));
+    }
+
+    let outcome = dispatch(state, &request.method, &request.params).await;
+
+    request.id.map(|id| RpcResponse::new(id, outcome))
+}
+
+async fn dispatch<P: Payload>(state: &NodeHandle<P>, method: &str, params: &Value) -> Result<Value, RpcError>
+{
+    match method
+    {
+        "getblockcount" => Ok(json!(state.height().await)),
+        "getfinalized" =>
+        {
+            let chain = state.chain().await;
+            let height = chain.finalized;
+
+            Ok(json!({ "height": height, "hash": chain.blocks.get(height as usize).map(|block| block.hash.clone()) }))
+        }
+        "getbestblockhash" => Ok(json!(state.tip().await.map(|block| block.hash))),
+        "getblock" =>
+        {
+            let chain = state.chain().await;
+
+            let block = match param(params, 0, "height").or_else(|| param(params, 0, "hash"))
+            {
+                Some(Value::Number(height)) => height.as_u64().and_then(|height| chain.blocks.get(height as usize)),
+                Some(Value::String(hash)) => chain.find_block(hash),
+                _ => return Err(rpc_error(INVALID_PARAMS, "Expected a block height or hash")),
+            };
+
+            block.map(|block| json!(block)).ok_or_else(|| rpc_error(INVALID_PARAMS, "Block not found"))
+        }
+        "getpeerinfo" => Ok(json!(state.peers().await.map_err(internal_error)?)),
+        "sendtransaction" =>
+        {
+            let from = param(params, 0, "from").and_then(Value::as_str);
+            let to = param(params, 1, "to").and_then(Value::as_str);
+            let amount = param(params, 2, "amount").and_then(Value::as_u64);
+
+            let (Some(from), Some(to), Some(amount)) = (from, to, amount) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected from, to and amount"));
+            };
+
+            let transaction = Transaction::new(from.to_owned(), to.to_owned(), amount);
+            let id = transaction.id.clone();
+
+            match state.submit_transaction(transaction).await
+            {
+                Err(Error::NodeStopped) => return Err(internal_error(Error::NodeStopped)),
+                Err(e) => return Err(rpc_error(INVALID_PARAMS, e.to_string())),
+                Ok(_) => {},
+            }
+
+            Ok(json!(id))
+        }
+        "gettxproof" =>
+        {
+            let Some(id) = param(params, 0, "id").and_then(Value::as_str) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected a transaction id"));
+            };
+
+            state.transaction_proof(id).await.map(|proof| json!(proof)).ok_or_else(|| rpc_error(INVALID_PARAMS, "Transaction not confirmed"))
+        }
+        "verifytxproof" =>
+        {
+            let proof = param(params, 0, "proof").cloned().and_then(|proof| serde_json::from_value::<MerkleProof>(proof).ok());
+            let merkle_root = param(params, 1, "merkle_root").and_then(Value::as_str);
+
+            let (Some(proof), Some(merkle_root)) = (proof, merkle_root) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected proof and merkle_root"));
+            };
+
+            Ok(json!(proof.verify(merkle_root)))
+        }
+        "verifytransaction" =>
+        {
+            let Some(id) = param(params, 0, "id").and_then(Value::as_str) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected a transaction id"));
+            };
+
+            let proof = match state.verify_transaction(id).await
+            {
+                Ok(proof) => proof,
+                Err(e @ (Error::NodeStopped | Error::RequestFailed(_))) => return Err(internal_error(e)),
+                Err(e) => return Err(rpc_error(INVALID_PARAMS, e.to_string())),
+            };
+
+            let confirmations = state.height().await.saturating_sub(proof.height) + 1;
+
+            Ok(json!({ "proof": proof, "confirmations": confirmations }))
+        }
+        "getvalidators" => Ok(json!(state.validators().await)),
+        "vote" =>
+        {
+            let validator = param(params, 0, "validator").and_then(Value::as_str);
+            let add = param(params, 1, "add").and_then(Value::as_bool);
+
+            let (Some(validator), Some(add)) = (validator, add) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected validator and add"));
+            };
+
+            authority::check_public_key(validator).map_err(|e| rpc_error(INVALID_PARAMS, e.to_string()))?;
+
+            let record = ConsensusRecord::Vote { validator: validator.to_owned(), add };
+            state.submit_record(record).await.map_err(|e| rpc_error(INVALID_PARAMS, e.to_string()))?;
+
+            Ok(json!(true))
+        }
+        "stake" | "unstake" =>
+        {
+            let staker = param(params, 0, "staker").and_then(Value::as_str);
+            let amount = param(params, 1, "amount").and_then(Value::as_u64);
+
+            let (Some(staker), Some(amount)) = (staker, amount) else
+            {
+                return Err(rpc_error(INVALID_PARAMS, "Expected staker and amount"));
+            };
+
+            let staker = staker.to_owned();
+            let record = if method == "stake" { ConsensusRecord::Deposit { staker, amount } } else { ConsensusRecord::Withdraw { staker, amount } };
+            state.submit_record(record).await.map_err(|e| rpc_error(INVALID_PARAMS, e.to_string()))?;
+
+            Ok(json!(true))
+        }
+        "getmempool" => Ok(json!(state.mempool().snapshot())),
+        "getstats" =>
+        {
+            let chain = state.chain().await;
+            Ok(json!(state.stats().report(&chain)))
+        }
+        "startmining" => Ok(json!(state.start_mining().await.map_err(internal_error)?)),
+        "stopmining" => Ok(json!(state.stop_mining().await.map_err(internal_error)?)),
+        "shutdown" =>
+        {
+            state.shutdown().await.map_err(internal_error)?;
+            Ok(json!("Shutting down"))
+        }
+        _ => Err(rpc_error(METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+    }
+}
+
+//Params may be positional or named.
+fn param<'a>(params: &'a Value, index: usize, name: &str) -> Option<&'a Value>
+{
+    params.get(index).or_else(|| params.get(name))
+}
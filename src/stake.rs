@@ -0,0 +1,684 @@
+use chrono::Utc;
+use libp2p::identity::ed25519::Keypair;
+use serde::Serialize;
+use sha2::{ Sha256, Digest };
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::authority::verify_signature;
+use crate::block::{ Block, BlockCandidate, MAX_BLOCK_RECORDS };
+use crate::consensus::{ BlockSignature, ConsensusEngine, ConsensusRecord, DoubleSign };
+use crate::error::Error;
+use crate::payload::Payload;
+use crate::transaction::Transaction;
+
+pub const DEFAULT_EPOCH_LENGTH: u64 = 10;
+pub const DEFAULT_GENESIS_STAKE: u64 = 100;
+
+//How far ahead a validator looks for a slot it leads before giving up on the current tip.
+const MAX_SLOT_SEARCH: u64 = 1000;
+
+//Stake and randomness as of a block. Leaders are drawn from the snapshot taken when the epoch started,
+//so deposits and withdrawals only count from the next epoch on.
+#[derive(Debug, Clone, Serialize)]
+pub struct StakeState
+{
+    pub stakes: BTreeMap<String, u64>,
+    //Unstaked funds. Withdrawals are credited here and deposits are paid from here.
+    pub balances: BTreeMap<String, u64>,
+    epoch_stakes: BTreeMap<String, u64>,
+    seed: String,
+    next_seed: String,
+}
+
+impl StakeState
+{
+    //Stake weighted draw from the epoch seed, the same slot always picks the same leader.
+    fn leader(&self, slot: u64) -> Option<&String>
+    {
+        let total: u64 = self.epoch_stakes.values().sum();
+
+        if total == 0
+        {
+            return None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}{slot}", self.seed));
+        let draw = u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap()) % total;
+
+        let mut passed = 0;
+
+        self.epoch_stakes.iter().find(|(_, stake)|
+        {
+            passed += *stake;
+            draw < passed
+        }).map(|(staker, _)| staker)
+    }
+
+    //The first block of an epoch freezes the stake table and switches to the randomness gathered during the last one.
+    fn enter(&self, height: u64, epoch_length: u64) -> Self
+    {
+        let mut next = self.clone();
+
+        if height.is_multiple_of(epoch_length)
+        {
+            next.epoch_stakes = next.stakes.clone();
+            next.seed = next.next_seed.clone();
+        }
+
+        next
+    }
+
+    //Moves funds from balance to stake for a deposit and back for a withdrawal. False, and nothing moved, when the
+    //side paying for it falls short.
+    fn transfer(&mut self, record: &ConsensusRecord) -> bool
+    {
+        let (from, to, staker, amount) = match record
+        {
+            ConsensusRecord::Deposit { staker, amount } => (&mut self.balances, &mut self.stakes, staker, *amount),
+            ConsensusRecord::Withdraw { staker, amount } => (&mut self.stakes, &mut self.balances, staker, *amount),
+            _ => return false,
+        };
+
+        let Some(available) = from.get_mut(staker).filter(|available| **available >= amount) else { return false };
+        *available -= amount;
+
+        let credited = to.entry(staker.clone()).or_default();
+        *credited = credited.saturating_add(amount);
+
+        true
+    }
+
+    fn apply<P>(&self, block: &Block<P>) -> Self
+    {
+        let mut next = self.clone();
+
+        for record in &block.records
+        {
+            match record
+            {
+                ConsensusRecord::Deposit { .. } | ConsensusRecord::Withdraw { .. } =>
+                {
+                    next.transfer(record);
+                }
+                ConsensusRecord::Reveal { signature } =>
+                {
+                    let mut hasher = Sha256::new();
+                    hasher.update(format!("{}{signature}", next.next_seed));
+                    next.next_seed = hex::encode(hasher.finalize());
+                }
+                ConsensusRecord::Slash(evidence) =>
+                {
+                    println!("Slashed {} for double signing in slot {}", evidence.signer, evidence.slot);
+                    next.stakes.remove(&evidence.signer);
+                    next.epoch_stakes.remove(&evidence.signer);
+                }
+                ConsensusRecord::Vote { .. } => {},
+            }
+        }
+
+        next.stakes.retain(|_, stake| *stake > 0);
+        next.balances.retain(|_, balance| *balance > 0);
+        next
+    }
+}
+
+#[derive(Debug)]
+struct SignedProposal
+{
+    height: u64,
+    hash: String,
+    signature: String,
+}
+
+//Stake weighted leaders per slot, with randomness mixed from every proposer's reveal (a RANDAO). Proposals are signed
+//over "{slot}:{hash}", so two of them in the same slot from one key are enough to prove double signing.
+#[derive(Debug)]
+pub struct ProofOfStake
+{
+    genesis: StakeState,
+    slot_time: u64,
+    epoch_length: u64,
+    key: Option<Keypair>,
+    //The state after each block by hash, with the block's height for pruning.
+    states: Mutex<HashMap<String, (u64, StakeState)>>,
+    proposals: Mutex<Vec<ConsensusRecord>>,
+    //The first proposal seen from each signer in each slot.
+    signed: Mutex<HashMap<(u64, String), SignedProposal>>,
+}
+
+impl ProofOfStake
+{
+    pub fn new(stakes: BTreeMap<String, u64>, slot_time: u64, epoch_length: u64, key: Option<Keypair>) -> Self
+    {
+        Self
+        {
+            genesis: StakeState
+            {
+                epoch_stakes: stakes.clone(),
+                stakes,
+                balances: BTreeMap::new(),
+                seed: String::from("genesis"),
+                next_seed: String::from("genesis"),
+            },
+            slot_time: slot_time.max(1),
+            epoch_length: epoch_length.max(1),
+            key,
+            states: Mutex::new(HashMap::new()),
+            proposals: Mutex::new(Vec::new()),
+            signed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn public_key(&self) -> Option<String>
+    {
+        self.key.as_ref().map(|key| hex::encode(key.public().to_bytes()))
+    }
+
+    fn slot(&self, timestamp: i64) -> u64
+    {
+        timestamp.max(0) as u64 / self.slot_time
+    }
+
+    fn state_after(&self, height: u64, hash: &str) -> Option<StakeState>
+    {
+        if height == 0
+        {
+            return Some(self.genesis.clone());
+        }
+
+        self.states.lock().unwrap().get(hash).map(|(_, state)| state.clone())
+    }
+
+    //Anyone with stake at genesis or after any block we track.
+    fn is_known_staker(&self, signer: &String) -> bool
+    {
+        self.genesis.stakes.contains_key(signer) || self.states.lock().unwrap().values().any(|(_, state)| state.stakes.contains_key(signer))
+    }
+
+    //The state leaders for the block at height are drawn from.
+    fn state_for(&self, height: u64, parent_hash: &str) -> Option<StakeState>
+    {
+        self.state_after(height - 1, parent_hash).map(|state| state.enter(height, self.epoch_length))
+    }
+
+    fn verify_records<P>(&self, block: &Block<P>, state: &StakeState, signer: &str) -> Result<(), Error>
+    {
+        let epoch = block.height / self.epoch_length;
+        let mut reveals = 0;
+        //Stake records are paid for in order, so two of them can't both spend the same funds.
+        let mut running = state.clone();
+
+        for record in &block.records
+        {
+            match record
+            {
+                ConsensusRecord::Reveal { signature } =>
+                {
+                    reveals += 1;
+
+                    if !verify_signature(signer, format!("randao:{epoch}").as_bytes(), signature)
+                    {
+                        return Err(Error::InvalidSignature);
+                    }
+                }
+                ConsensusRecord::Deposit { staker, .. } | ConsensusRecord::Withdraw { staker, .. } =>
+                {
+                    if staker != signer || !running.transfer(record)
+                    {
+                        return Err(Error::UnsupportedRecord);
+                    }
+                }
+                ConsensusRecord::Slash(evidence) if state.stakes.contains_key(&evidence.signer) => check_evidence(evidence)?,
+                _ => return Err(Error::UnsupportedRecord),
+            }
+        }
+
+        if reveals != 1
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Payload> ConsensusEngine<P> for ProofOfStake
+{
+    //Aims the candidate at the next slot this node is drawn for, with its reveal and any queued stake records.
+    fn prepare(&self, parent: &Block<P>, data: P, transactions: Vec<Transaction>) -> BlockCandidate<P>
+    {
+        let height = parent.height + 1;
+        let first_slot = self.slot(Utc::now().timestamp()).max(self.slot(parent.timestamp) + 1);
+        let mut slot = first_slot;
+        let mut records = Vec::new();
+
+        if let (Some(state), Some(key)) = (self.state_for(height, &parent.hash), &self.key)
+        {
+            let public = hex::encode(key.public().to_bytes());
+
+            slot = (first_slot..first_slot + MAX_SLOT_SEARCH).find(|slot| state.leader(*slot) == Some(&public)).unwrap_or(first_slot);
+
+            let reveal = key.sign(format!("randao:{}", height / self.epoch_length).as_bytes());
+            records.push(ConsensusRecord::Reveal { signature: hex::encode(reveal) });
+
+            let mut running = state.clone();
+
+            records.extend(self.proposals.lock().unwrap().iter().filter(|record| match record
+            {
+                ConsensusRecord::Deposit { staker, .. } | ConsensusRecord::Withdraw { staker, .. } => staker == &public && running.transfer(record),
+                ConsensusRecord::Slash(evidence) => state.stakes.contains_key(&evidence.signer),
+                _ => false,
+            }).take(MAX_BLOCK_RECORDS - 1).cloned());
+        }
+
+        let mut candidate = BlockCandidate::new(parent, data, transactions);
+        candidate.timestamp = (slot * self.slot_time) as i64;
+        candidate.records = records;
+
+        candidate
+    }
+
+    fn ready_in(&self, candidate: &BlockCandidate<P>) -> Option<Duration>
+    {
+        let public = self.public_key()?;
+        let state = self.state_for(candidate.height, &candidate.previous_hash)?;
+
+        if state.leader(self.slot(candidate.timestamp)) != Some(&public)
+        {
+            return None;
+        }
+
+        Some(Duration::from_secs((candidate.timestamp - Utc::now().timestamp()).max(0) as u64))
+    }
+
+    fn seal(&self, candidate: &BlockCandidate<P>, nonce: u64) -> Option<Block<P>>
+    {
+        let key = self.key.as_ref()?;
+
+        let hash = candidate.hash(nonce);
+        let signature = BlockSignature
+        {
+            signer: hex::encode(key.public().to_bytes()),
+            signature: hex::encode(key.sign(proposal_message(self.slot(candidate.timestamp), &hash).as_bytes())),
+        };
+
+        let mut block = candidate.clone().into_block(hash, nonce);
+        block.signature = Some(signature);
+
+        Some(block)
+    }
+
+    fn verify_seal(&self, block: &Block<P>, parent: &Block<P>) -> Result<(), Error>
+    {
+        let state = self.state_for(block.height, &parent.hash).ok_or(Error::UnauthorizedSigner)?;
+
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        let slot = self.slot(block.timestamp);
+
+        if parent.height > 0 && slot <= self.slot(parent.timestamp)
+        {
+            return Err(Error::InvalidSlot);
+        }
+
+        let signature = block.signature.as_ref().ok_or(Error::InvalidSignature)?;
+
+        if state.leader(slot) != Some(&signature.signer)
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !verify_signature(&signature.signer, proposal_message(slot, &block.hash).as_bytes(), &signature.signature)
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        self.verify_records(block, &state, &signature.signer)?;
+
+        //A leader can't sign for a slot that hasn't come up yet, give or take a slot of clock drift.
+        if (slot * self.slot_time) as i64 > Utc::now().timestamp() + self.slot_time as i64
+        {
+            return Err(Error::TimestampTooFarAhead);
+        }
+
+        Ok(())
+    }
+
+    //Without the parent the slot's leader is unknown, but the signer has to hold stake we know of and the signature has to hold.
+    fn verify_header(&self, block: &Block<P>) -> Result<(), Error>
+    {
+        if block.recalculate_hash() != block.hash
+        {
+            return Err(Error::InvalidHash);
+        }
+
+        let signature = block.signature.as_ref().ok_or(Error::InvalidSignature)?;
+
+        if !self.is_known_staker(&signature.signer)
+        {
+            return Err(Error::UnauthorizedSigner);
+        }
+
+        if !verify_signature(&signature.signer, proposal_message(self.slot(block.timestamp), &block.hash).as_bytes(), &signature.signature)
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    fn accept(&self, block: &Block<P>, parent: &Block<P>)
+    {
+        let Some(state) = self.state_for(block.height, &parent.hash) else { return };
+
+        self.proposals.lock().unwrap().retain(|record| !block.records.contains(record));
+        self.states.lock().unwrap().insert(block.hash.clone(), (block.height, state.apply(block)));
+    }
+
+    //Evidence below the pruned height could only slash a proposal that can no longer be reorganized away anyway.
+    fn prune(&self, height: u64)
+    {
+        self.states.lock().unwrap().retain(|_, (block_height, _)| *block_height >= height);
+        self.signed.lock().unwrap().retain(|_, proposal| proposal.height >= height);
+    }
+
+    fn weight(&self, _block: &Block<P>) -> u128
+    {
+        1
+    }
+
+    //Remembers one signed proposal per signer and slot, a second one with a different hash becomes slashing evidence.
+    fn observe(&self, block: &Block<P>)
+    {
+        let Some(signature) = &block.signature else { return };
+        let slot = self.slot(block.timestamp);
+
+        if !verify_signature(&signature.signer, proposal_message(slot, &block.hash).as_bytes(), &signature.signature)
+        {
+            return;
+        }
+
+        let mut signed = self.signed.lock().unwrap();
+
+        match signed.get(&(slot, signature.signer.clone()))
+        {
+            Some(first) if first.hash == block.hash => {},
+            Some(first) =>
+            {
+                println!("{} signed two blocks in slot {slot}, queueing slashing evidence", signature.signer);
+
+                let evidence = ConsensusRecord::Slash(DoubleSign
+                {
+                    signer: signature.signer.clone(),
+                    slot,
+                    first_hash: first.hash.clone(),
+                    first_signature: first.signature.clone(),
+                    second_hash: block.hash.clone(),
+                    second_signature: signature.signature.clone(),
+                });
+
+                let mut proposals = self.proposals.lock().unwrap();
+
+                if !proposals.contains(&evidence)
+                {
+                    proposals.push(evidence);
+                }
+            }
+            None =>
+            {
+                signed.insert((slot, signature.signer.clone()), SignedProposal { height: block.height, hash: block.hash.clone(), signature: signature.signature.clone() });
+            }
+        }
+    }
+
+    fn submit_record(&self, record: ConsensusRecord) -> Result<(), Error>
+    {
+        match &record
+        {
+            ConsensusRecord::Deposit { amount, .. } | ConsensusRecord::Withdraw { amount, .. } if *amount > 0 => {},
+            ConsensusRecord::Slash(evidence) => check_evidence(evidence)?,
+            _ => return Err(Error::UnsupportedRecord),
+        }
+
+        let mut proposals = self.proposals.lock().unwrap();
+
+        if !proposals.contains(&record)
+        {
+            proposals.push(record);
+        }
+
+        Ok(())
+    }
+
+    fn validators(&self, tip: &Block<P>) -> Vec<String>
+    {
+        self.state_after(tip.height, &tip.hash).map(|state| state.stakes.into_keys().collect()).unwrap_or_default()
+    }
+}
+
+fn proposal_message(slot: u64, hash: &str) -> String
+{
+    format!("{slot}:{hash}")
+}
+
+fn check_evidence(evidence: &DoubleSign) -> Result<(), Error>
+{
+    let first = proposal_message(evidence.slot, &evidence.first_hash);
+    let second = proposal_message(evidence.slot, &evidence.second_hash);
+
+    if evidence.first_hash == evidence.second_hash
+        || !verify_signature(&evidence.signer, first.as_bytes(), &evidence.first_signature)
+        || !verify_signature(&evidence.signer, second.as_bytes(), &evidence.second_signature)
+    {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::block::BlockState;
+
+    fn engine() -> (ProofOfStake, String)
+    {
+        let key = Keypair::generate();
+        let public = hex::encode(key.public().to_bytes());
+        let stakes = BTreeMap::from([(public.clone(), DEFAULT_GENESIS_STAKE)]);
+
+        (ProofOfStake::new(stakes, 1, DEFAULT_EPOCH_LENGTH, Some(key)), public)
+    }
+
+    //Backdated so the first slots after it have already come up.
+    fn genesis() -> Block
+    {
+        let mut chain = BlockState::new();
+        chain.create_genesis_block();
+
+        let mut genesis = chain.blocks.remove(0);
+        genesis.timestamp -= 60;
+        genesis
+    }
+
+    //Prepares and seals the next block the way the node does, with whatever records were submitted.
+    fn propose(engine: &ProofOfStake, parent: &Block) -> Block
+    {
+        let candidate = engine.prepare(parent, String::from("stake"), Vec::new());
+        engine.seal(&candidate, 0).unwrap()
+    }
+
+    fn submit(engine: &ProofOfStake, record: ConsensusRecord)
+    {
+        ConsensusEngine::<String>::submit_record(engine, record).unwrap();
+    }
+
+    fn state(engine: &ProofOfStake, block: &Block) -> StakeState
+    {
+        engine.state_after(block.height, &block.hash).unwrap()
+    }
+
+    #[test]
+    fn transfers_need_funds_on_the_paying_side()
+    {
+        let (engine, public) = engine();
+        let mut state = engine.genesis.clone();
+
+        assert!(!state.transfer(&ConsensusRecord::Deposit { staker: public.clone(), amount: 1 }));
+        assert!(state.transfer(&ConsensusRecord::Withdraw { staker: public.clone(), amount: 30 }));
+        assert!(state.transfer(&ConsensusRecord::Deposit { staker: public.clone(), amount: 20 }));
+        assert!(!state.transfer(&ConsensusRecord::Deposit { staker: public.clone(), amount: 20 }));
+
+        assert_eq!(state.stakes[&public], DEFAULT_GENESIS_STAKE - 10);
+        assert_eq!(state.balances[&public], 10);
+    }
+
+    #[test]
+    fn withdrawn_stake_pays_for_a_later_deposit()
+    {
+        let (engine, public) = engine();
+        let genesis = genesis();
+
+        submit(&engine, ConsensusRecord::Withdraw { staker: public.clone(), amount: 40 });
+        let first = propose(&engine, &genesis);
+        engine.verify_seal(&first, &genesis).unwrap();
+        engine.accept(&first, &genesis);
+
+        submit(&engine, ConsensusRecord::Deposit { staker: public.clone(), amount: 25 });
+        let second = propose(&engine, &first);
+        engine.verify_seal(&second, &first).unwrap();
+        engine.accept(&second, &first);
+
+        let state = state(&engine, &second);
+        assert_eq!(state.stakes[&public], DEFAULT_GENESIS_STAKE - 15);
+        assert_eq!(state.balances[&public], 15);
+    }
+
+    #[test]
+    fn unfunded_deposits_stay_out_of_blocks()
+    {
+        let (engine, public) = engine();
+        let genesis = genesis();
+
+        submit(&engine, ConsensusRecord::Deposit { staker: public.clone(), amount: 5 });
+        let block = propose(&engine, &genesis);
+
+        assert!(!block.records.iter().any(|record| matches!(record, ConsensusRecord::Deposit { .. })));
+    }
+
+    #[test]
+    fn blocks_spending_the_same_funds_twice_are_rejected()
+    {
+        let (engine, public) = engine();
+        let genesis = genesis();
+
+        let mut candidate = engine.prepare(&genesis, String::from("stake"), Vec::new());
+        candidate.records.push(ConsensusRecord::Withdraw { staker: public.clone(), amount: 60 });
+        candidate.records.push(ConsensusRecord::Withdraw { staker: public.clone(), amount: 60 });
+
+        let block = engine.seal(&candidate, 0).unwrap();
+        assert!(matches!(engine.verify_seal(&block, &genesis), Err(Error::UnsupportedRecord)));
+
+        candidate.records.pop();
+        let block = engine.seal(&candidate, 0).unwrap();
+        assert!(engine.verify_seal(&block, &genesis).is_ok());
+    }
+
+    #[test]
+    fn stake_records_are_only_valid_from_the_staker()
+    {
+        let (engine, _) = engine();
+        let (other, _) = self::engine();
+        let genesis = genesis();
+
+        let mut candidate = engine.prepare(&genesis, String::from("stake"), Vec::new());
+        candidate.records.push(ConsensusRecord::Withdraw { staker: other.public_key().unwrap(), amount: 1 });
+
+        let block = engine.seal(&candidate, 0).unwrap();
+        assert!(matches!(engine.verify_seal(&block, &genesis), Err(Error::UnsupportedRecord)));
+    }
+
+    #[test]
+    fn proposals_are_signed_over_their_slot()
+    {
+        let (engine, public) = engine();
+        let genesis = genesis();
+
+        let block = propose(&engine, &genesis);
+        engine.verify_seal(&block, &genesis).unwrap();
+        engine.verify_header(&block).unwrap();
+
+        //The same key over the bare hash, the way a proof of authority block is signed.
+        let key = engine.key.as_ref().unwrap();
+        let mut bare = block.clone();
+        bare.signature = Some(BlockSignature { signer: public, signature: hex::encode(key.sign(block.hash.as_bytes())) });
+
+        assert!(matches!(engine.verify_seal(&bare, &genesis), Err(Error::InvalidSignature)));
+        assert!(matches!(engine.verify_header(&bare), Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn only_stakers_may_propose()
+    {
+        let (engine, _) = engine();
+        let (outsider, _) = self::engine();
+        let genesis = genesis();
+
+        let block = propose(&outsider, &genesis);
+
+        assert!(matches!(engine.verify_seal(&block, &genesis), Err(Error::UnauthorizedSigner)));
+        assert!(matches!(engine.verify_header(&block), Err(Error::UnauthorizedSigner)));
+    }
+
+    #[test]
+    fn proposals_for_later_slots_wait()
+    {
+        let (engine, _) = engine();
+        let genesis = genesis();
+
+        let mut candidate = engine.prepare(&genesis, String::from("stake"), Vec::new());
+        candidate.timestamp = Utc::now().timestamp() + 30;
+        let early = engine.seal(&candidate, 0).unwrap();
+
+        assert!(matches!(engine.verify_seal(&early, &genesis), Err(Error::TimestampTooFarAhead)));
+    }
+
+    #[test]
+    fn signing_twice_in_a_slot_gets_the_stake_slashed()
+    {
+        let (engine, public) = engine();
+        let (witness, _) = self::engine();
+        let genesis = genesis();
+
+        let first = propose(&engine, &genesis);
+        let mut candidate = engine.prepare(&genesis, String::from("equivocation"), Vec::new());
+        candidate.timestamp = first.timestamp;
+        let second = engine.seal(&candidate, 0).unwrap();
+        assert_ne!(first.hash, second.hash);
+
+        ConsensusEngine::<String>::observe(&witness, &first);
+        ConsensusEngine::<String>::observe(&witness, &second);
+
+        let evidence = witness.proposals.lock().unwrap().clone();
+        assert!(matches!(evidence.as_slice(), [ConsensusRecord::Slash(evidence)] if evidence.signer == public));
+
+        //Handed to the offender's own engine so there is someone to seal the block carrying it.
+        for record in evidence
+        {
+            submit(&engine, record);
+        }
+
+        let block = propose(&engine, &genesis);
+        engine.verify_seal(&block, &genesis).unwrap();
+        engine.accept(&block, &genesis);
+
+        assert!(!state(&engine, &block).stakes.contains_key(&public));
+    }
+}
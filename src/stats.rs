@@ -0,0 +1,285 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{ AtomicI64, AtomicU64, Ordering };
+
+use crate::block::BlockState;
+use crate::payload::Payload;
+use crate::wire::Encoding;
+
+//One CBOR message in this many is also sized as JSON to estimate the savings, serializing every one twice would cost
+//more than the number is worth.
+const JSON_SAMPLE_RATE: u64 = 16;
+
+#[derive(Debug, Default)]
+pub struct WorkerStats
+{
+    hashes: AtomicU64,
+    job_hashes: AtomicU64,
+    job_started: AtomicI64,
+}
+
+impl WorkerStats
+{
+    pub fn start_job(&self)
+    {
+        self.job_hashes.store(0, Ordering::Relaxed);
+        self.job_started.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn add_hashes(&self, count: u64)
+    {
+        self.hashes.fetch_add(count, Ordering::Relaxed);
+        self.job_hashes.fetch_add(count, Ordering::Relaxed);
+    }
+
+    //Hashes per second over the job the worker is currently on, so a long idle period doesn't drag the number down.
+    fn hashrate(&self) -> f64
+    {
+        let started = self.job_started.load(Ordering::Relaxed);
+
+        if started == 0
+        {
+            return 0.0;
+        }
+
+        let elapsed = (Utc::now().timestamp_millis() - started).max(1) as f64 / 1000.0;
+
+        self.job_hashes.load(Ordering::Relaxed) as f64 / elapsed
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats
+{
+    pub workers: Vec<WorkerStats>,
+    blocks_found: AtomicU64,
+    stale_work: AtomicU64,
+    orphaned: AtomicU64,
+    sync_sent: AtomicU64,
+    sync_received: AtomicU64,
+    gossip_sent: AtomicU64,
+    gossip_received: AtomicU64,
+    messages_sent: AtomicU64,
+    //Bytes of the sampled messages as sent, and the same messages as JSON.
+    sampled: AtomicU64,
+    sampled_as_json: AtomicU64,
+}
+
+impl Stats
+{
+    pub fn new(workers: usize) -> Self
+    {
+        Self
+        {
+            workers: (0..workers.max(1)).map(|_| WorkerStats::default()).collect(),
+            blocks_found: AtomicU64::new(0),
+            stale_work: AtomicU64::new(0),
+            orphaned: AtomicU64::new(0),
+            sync_sent: AtomicU64::new(0),
+            sync_received: AtomicU64::new(0),
+            gossip_sent: AtomicU64::new(0),
+            gossip_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+            sampled_as_json: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_sync_sent<M: Serialize>(&self, encoding: Encoding, bytes: usize, message: &M)
+    {
+        self.sync_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.sample(encoding, bytes, message);
+    }
+
+    pub fn record_sync_received(&self, bytes: usize)
+    {
+        self.sync_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_gossip_sent<M: Serialize>(&self, encoding: Encoding, bytes: usize, message: &M)
+    {
+        self.gossip_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.sample(encoding, bytes, message);
+    }
+
+    pub fn record_gossip_received(&self, bytes: usize)
+    {
+        self.gossip_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    //A JSON message is its own JSON size, a CBOR one is serialized again only when its turn in the sample comes up.
+    fn sample<M: Serialize>(&self, encoding: Encoding, bytes: usize, message: &M)
+    {
+        let json_bytes = match encoding
+        {
+            Encoding::Json => bytes,
+            Encoding::Cbor if self.messages_sent.fetch_add(1, Ordering::Relaxed).is_multiple_of(JSON_SAMPLE_RATE) =>
+            {
+                let Ok(json) = serde_json::to_vec(message) else { return };
+                json.len()
+            },
+            Encoding::Cbor => return,
+        };
+
+        self.sampled.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.sampled_as_json.fetch_add(json_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_block_found(&self)
+    {
+        self.blocks_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //The workers gave up on a candidate because a new tip arrived before any of them sealed it.
+    pub fn record_stale_work(&self)
+    {
+        self.stale_work.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //Validly sealed blocks that lost fork choice, whether they never made it onto our chain or a reorg dropped them.
+    pub fn record_orphans(&self, count: usize)
+    {
+        self.orphaned.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn report<P: Payload>(&self, chain: &BlockState<P>) -> StatsReport
+    {
+        let workers: Vec<WorkerReport> = self.workers.iter().enumerate().map(|(worker, stats)| WorkerReport
+        {
+            worker,
+            hashrate: stats.hashrate(),
+            hashes: stats.hashes.load(Ordering::Relaxed),
+        }).collect();
+
+        let mining = MiningReport
+        {
+            hashrate: workers.iter().map(|worker| worker.hashrate).sum(),
+            workers,
+            blocks_found: self.blocks_found.load(Ordering::Relaxed),
+            stale_work: self.stale_work.load(Ordering::Relaxed),
+        };
+
+        let orphaned = self.orphaned.load(Ordering::Relaxed);
+        let accepted = chain.blocks.len().saturating_sub(1) as u64;
+
+        //Measured from block 1, genesis carries whatever time this node first started.
+        let average_block_interval = match (chain.blocks.get(1), chain.blocks.last())
+        {
+            (Some(first), Some(last)) if accepted > 1 => (last.timestamp - first.timestamp) as f64 / (accepted - 1) as f64,
+            _ => 0.0,
+        };
+
+        //Only the heights where the work per block changed, genesis is left out as it's never sealed.
+        let engine = chain.engine();
+        let mut difficulty_history: Vec<DifficultyChange> = Vec::new();
+
+        for block in chain.blocks.iter().skip(1)
+        {
+            let difficulty = engine.weight(block);
+
+            if difficulty_history.last().is_none_or(|change| change.difficulty != difficulty)
+            {
+                difficulty_history.push(DifficultyChange { height: block.height, difficulty });
+            }
+        }
+
+        let chain = ChainReport
+        {
+            height: chain.blocks.last().map_or(0, |block| block.height),
+            average_block_interval,
+            difficulty_history,
+            orphaned,
+            orphan_rate: if accepted + orphaned == 0 { 0.0 } else { orphaned as f64 / (accepted + orphaned) as f64 },
+        };
+
+        let sampled = self.sampled.load(Ordering::Relaxed);
+        let sampled_as_json = self.sampled_as_json.load(Ordering::Relaxed);
+
+        let network = NetworkReport
+        {
+            sync_sent: self.sync_sent.load(Ordering::Relaxed),
+            sync_received: self.sync_received.load(Ordering::Relaxed),
+            gossip_sent: self.gossip_sent.load(Ordering::Relaxed),
+            gossip_received: self.gossip_received.load(Ordering::Relaxed),
+            saved: if sampled_as_json == 0 { 0.0 } else { 1.0 - sampled as f64 / sampled_as_json as f64 },
+        };
+
+        StatsReport { mining, chain, network }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerReport
+{
+    pub worker: usize,
+    pub hashrate: f64,
+    pub hashes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MiningReport
+{
+    pub hashrate: f64,
+    pub workers: Vec<WorkerReport>,
+    pub blocks_found: u64,
+    pub stale_work: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DifficultyChange
+{
+    pub height: u64,
+    //The work a block at this height adds to its branch, as the engine weighs it for fork choice.
+    pub difficulty: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainReport
+{
+    pub height: u64,
+    pub average_block_interval: f64,
+    pub difficulty_history: Vec<DifficultyChange>,
+    pub orphaned: u64,
+    pub orphan_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetworkReport
+{
+    pub sync_sent: u64,
+    pub sync_received: u64,
+    pub gossip_sent: u64,
+    pub gossip_received: u64,
+    //Estimated share of what the sent messages would have taken as JSON that we didn't have to send.
+    pub saved: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsReport
+{
+    pub mining: MiningReport,
+    pub chain: ChainReport,
+    pub network: NetworkReport,
+}
+
+impl fmt::Display for StatsReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        writeln!(f, "Hashrate: {:.0} H/s\nBlocks Found: {}\nStale Work: {}", self.mining.hashrate, self.mining.blocks_found, self.mining.stale_work)?;
+
+        for worker in &self.mining.workers
+        {
+            writeln!(f, "  Worker {}: {:.0} H/s ({} hashes)", worker.worker, worker.hashrate, worker.hashes)?;
+        }
+
+        let difficulty = self.chain.difficulty_history.last().map_or(0, |change| change.difficulty);
+
+        writeln!(f, "Height: {}\nAverage Block Interval: {:.1}s\nDifficulty: {}\nOrphan Rate: {:.2}% ({} orphaned)", self.chain.height, self.chain.average_block_interval, difficulty, self.chain.orphan_rate * 100.0, self.chain.orphaned)?;
+
+        writeln!(f, "Sync Traffic: {} bytes sent, {} bytes received", self.network.sync_sent, self.network.sync_received)?;
+        writeln!(f, "Gossip Traffic: {} bytes sent, {} bytes received", self.network.gossip_sent, self.network.gossip_received)?;
+        writeln!(f, "Wire Savings: about {:.1}% smaller than JSON", self.network.saved * 100.0)
+    }
+}
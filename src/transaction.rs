@@ -0,0 +1,151 @@
+use chrono::Utc;
+use serde::{ Serialize, Deserialize };
+use sha2::{ Sha256, Digest };
+use std::collections::{ HashMap, VecDeque };
+use std::sync::Mutex;
+
+use crate::error::Error;
+use crate::merkle::merkle_root;
+
+//Upper bound on how many pending transactions go into a single candidate.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 100;
+//Upper bound on pending transactions, new ones are refused once it's reached.
+pub const MAX_MEMPOOL_SIZE: usize = 5000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Transaction
+{
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+impl Transaction
+{
+    pub fn new(from: String, to: String, amount: u64) -> Self
+    {
+        let mut transaction = Self
+        {
+            id: String::new(),
+            from,
+            to,
+            amount,
+            timestamp: Utc::now().timestamp(),
+        };
+
+        transaction.id = transaction.calculate_id();
+        transaction
+    }
+
+    pub fn calculate_id(&self) -> String
+    {
+        let mut hasher = Sha256::new();
+
+        //Length prefixed, so moving characters from one address to the other changes the id.
+        for field in [&self.from, &self.to]
+        {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+
+        hasher.update(self.amount.to_le_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn verify(&self) -> Result<(), Error>
+    {
+        if self.from.is_empty() || self.to.is_empty() || self.id != self.calculate_id()
+        {
+            return Err(Error::InvalidTransaction);
+        }
+
+        Ok(())
+    }
+
+    pub fn involves(&self, address: &str) -> bool
+    {
+        self.from == address || self.to == address
+    }
+}
+
+//Commits a block's hash to the transactions it carries, one Merkle leaf per transaction id.
+pub fn transactions_root(transactions: &[Transaction]) -> String
+{
+    let ids: Vec<String> = transactions.iter().map(|transaction| transaction.id.clone()).collect();
+
+    merkle_root(&ids)
+}
+
+//Pending transactions by id, plus the order they arrived in for building candidates.
+#[derive(Debug, Default)]
+struct Pending
+{
+    transactions: HashMap<String, Transaction>,
+    order: VecDeque<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Mempool
+{
+    pending: Mutex<Pending>,
+}
+
+impl Mempool
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    //Returns false when the transaction is already pending. Whether it's already confirmed is for the caller to check
+    //against its chain.
+    pub fn add(&self, transaction: Transaction) -> Result<bool, Error>
+    {
+        transaction.verify()?;
+
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.transactions.contains_key(&transaction.id)
+        {
+            return Ok(false);
+        }
+
+        if pending.transactions.len() >= MAX_MEMPOOL_SIZE
+        {
+            return Err(Error::MempoolFull);
+        }
+
+        pending.order.push_back(transaction.id.clone());
+        pending.transactions.insert(transaction.id.clone(), transaction);
+        Ok(true)
+    }
+
+    pub fn select(&self, limit: usize) -> Vec<Transaction>
+    {
+        let pending = self.pending.lock().unwrap();
+
+        pending.order.iter().filter_map(|id| pending.transactions.get(id)).take(limit).cloned().collect()
+    }
+
+    pub fn remove_included(&self, transactions: &[Transaction])
+    {
+        let mut pending = self.pending.lock().unwrap();
+
+        for transaction in transactions
+        {
+            pending.transactions.remove(&transaction.id);
+        }
+
+        let Pending { transactions, order } = &mut *pending;
+        order.retain(|id| transactions.contains_key(id));
+    }
+
+    pub fn snapshot(&self) -> Vec<Transaction>
+    {
+        self.select(usize::MAX)
+    }
+}
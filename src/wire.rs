@@ -0,0 +1,59 @@
+use serde::{ Serialize, de::DeserializeOwned };
+
+use crate::error::Error;
+
+//Binary gossip messages open with this byte, JSON ones with '{'. Bumped whenever the binary layout changes.
+pub const CBOR_V1: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding
+{
+    Json,
+    Cbor,
+}
+
+impl Encoding
+{
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error>
+    {
+        match self
+        {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::Cbor => cbor4ii::serde::to_vec(Vec::new(), value).map_err(|_| Error::FailedSerialization),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>
+    {
+        match self
+        {
+            Self::Json => Ok(serde_json::from_slice(bytes)?),
+            Self::Cbor => cbor4ii::serde::from_slice(bytes).map_err(|_| Error::FailedSerialization),
+        }
+    }
+}
+
+//Gossip has no negotiation, so each message says how it was encoded.
+pub fn encode_message<T: Serialize>(encoding: Encoding, value: &T) -> Result<Vec<u8>, Error>
+{
+    match encoding
+    {
+        Encoding::Json => encoding.encode(value),
+        Encoding::Cbor =>
+        {
+            let mut bytes = vec![CBOR_V1];
+            bytes.extend(encoding.encode(value)?);
+            Ok(bytes)
+        }
+    }
+}
+
+pub fn decode_message<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error>
+{
+    match bytes.split_first()
+    {
+        Some((&CBOR_V1, body)) => Encoding::Cbor.decode(body),
+        Some((b'{', _)) => Encoding::Json.decode(bytes),
+        _ => Err(Error::FailedSerialization),
+    }
+}
@@ -0,0 +1,112 @@
+use axum::{ Router, routing::get, extract::{ State, ws::{ Message, WebSocket, WebSocketUpgrade } }, response::Response };
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use std::collections::HashSet;
+
+use crate::events::NodeEvent;
+use crate::node::NodeHandle;
+use crate::payload::Payload;
+
+const EVENTS: [&str; 6] = ["newBlock", "reorg", "finalized", "newTransaction", "peerConnected", "peerDisconnected"];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Subscription
+{
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+}
+
+pub fn serve<P: Payload>(listener: TcpListener, node: NodeHandle<P>)
+{
+    let router = Router::new()
+        .route("/ws", get(upgrade::<P>))
+        .with_state(node);
+
+    tokio::spawn(async move
+    {
+        if let Err(e) = axum::serve(listener, router).await
+        {
+            println!("WebSocket server stopped! {e}");
+        }
+    });
+}
+
+async fn upgrade<P: Payload>(State(node): State<NodeHandle<P>>, ws: WebSocketUpgrade) -> Response
+{
+    ws.on_upgrade(move |socket| handle_socket(socket, node.subscribe()))
+}
+
+//Clients start with no subscriptions and send {"subscribe": ["newBlock", ...]} or {"unsubscribe": [...]}.
+async fn handle_socket<P: Payload>(mut socket: WebSocket, mut events: broadcast::Receiver<NodeEvent<P>>)
+{
+    let mut subscribed: HashSet<String> = HashSet::new();
+
+    loop
+    {
+        tokio::select!
+        {
+            message = socket.recv() =>
+            {
+                let reply = match message
+                {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str::<Subscription>(&text)
+                    {
+                        Ok(Subscription::Subscribe(names)) =>
+                        {
+                            if let Some(unknown) = names.iter().find(|name| !EVENTS.contains(&name.as_str()))
+                            {
+                                json!({ "error": format!("Unknown event {unknown}") })
+                            }
+                            else
+                            {
+                                subscribed.extend(names);
+                                json!({ "subscribed": subscribed })
+                            }
+                        }
+                        Ok(Subscription::Unsubscribe(names)) =>
+                        {
+                            subscribed.retain(|name| !names.contains(name));
+                            json!({ "subscribed": subscribed })
+                        }
+                        Err(e) => json!({ "error": e.to_string() }),
+                    },
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                if socket.send(Message::Text(reply.to_string().into())).await.is_err()
+                {
+                    break;
+                }
+            }
+            event = events.recv() =>
+            {
+                let event = match event
+                {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) =>
+                    {
+                        println!("WebSocket client lagged, {skipped} events skipped");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if !subscribed.contains(event.name())
+                {
+                    continue;
+                }
+
+                let Ok(serialized) = serde_json::to_string(&event) else { continue };
+
+                if socket.send(Message::Text(serialized.into())).await.is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}